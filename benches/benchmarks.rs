@@ -1,34 +1,41 @@
-use std::{fs::remove_file, sync::Arc};
+use std::{fs::remove_dir_all, sync::Arc, env::temp_dir, thread, time::{Duration, Instant}};
 
 use criterion::{Criterion, criterion_group, criterion_main};
-use rustDB::{storage::{utils::create_file, utils::append_block, disk_manager::read_block, folder::Folder}, buffer::tuple::{RowTable, DatumTypes, Datum, TupleOps, PageBuffer, Table}, operator::Select};
+use rustDB::{storage::{utils::create_file, utils::append_block, disk_manager::read_block, folder::Folder, database::Database}, buffer::{tuple::{RowTable, DatumTypes, Datum, TupleOps, PageBuffer, Table}, keepers::Policy, Buff}, operator::Select};
 
 
 
 pub fn block_read_benchmark(c: &mut Criterion) {
-    let file_id = 20;
-    create_file(&file_id.to_string()).expect("Could not create file");
+    let db = Database::open(temp_dir().join("rustDB_bench_read_block")).unwrap();
+    let file_id: u128 = 20;
+    create_file(&db, &file_id.to_string()).expect("Could not create file");
     for _ in 0..20 {
-        append_block(&file_id.to_string()).expect("Could not append block to file");
+        append_block(&db, &file_id.to_string()).expect("Could not append block to file");
     }
     c.bench_function("read_block", |b| b.iter(|| {
         for i in 0..20 {
-            read_block((file_id << 32) | i & 0xFFFFFFFF);
+            read_block(&db, (file_id << 64) | i & 0xFFFFFFFF).unwrap();
         }
     }));
-    remove_file("C:/Users/vikra/rustDB/".to_owned() + &file_id.to_string()).expect("Could not delete benchmark file");
+    remove_dir_all(db.root()).expect("Could not delete benchmark directory");
 }
 
 pub fn seq_scan_benchmark(c: &mut Criterion) {
         let t_id = "test".to_string();
-        let f = Arc::new(Folder::new().unwrap());
-        let mut t = RowTable::create(f, &t_id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
-        let buf = Arc::new(PageBuffer::new(1001));
-        let mut tuple = vec![Datum::Int(10), Datum::Int(20)];
-        for _ in 0..100000 {
-            t.add(Arc::clone(&buf), tuple).unwrap();
-            tuple = vec![Datum::Int(10), Datum::Int(10), Datum::Int(10), Datum::Int(10), Datum::Int(10), Datum::Int(10)];
-        }
+        let root = temp_dir().join("rustDB_bench_seq_scan");
+        let _ = remove_dir_all(&root);
+        let db = Database::open(&root).unwrap();
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(db, 1001));
+        f.update_table(&t_id, |t: &mut RowTable| {
+            for _ in 0..100000 {
+                t.add(Arc::clone(&buf), vec![Datum::Int(10), Datum::Int(20)])?;
+            }
+            Ok(())
+        }).unwrap();
+        let t = RowTable::new(f, &t_id).unwrap();
 
         c.bench_function("seq_scan", |b| b.iter(|| {
             let s = Select::new(t.clone(), Arc::clone(&buf), |_| true).into_iter();
@@ -37,5 +44,81 @@ pub fn seq_scan_benchmark(c: &mut Criterion) {
     
 }
 
-criterion_group!(benches, block_read_benchmark, seq_scan_benchmark);
-criterion_main!(benches);
\ No newline at end of file
+/// Page ids of a probe scan over `scan` blocks of file 2 where every `every`-th access is a point
+/// lookup into a build side of `hot` blocks of file 1, as in a hash join.
+fn scan_and_lookups(scan: u128, hot: u128, every: u128) -> Vec<u128> {
+    let mut seed: u128 = 0x2545F491;
+    (0..scan).map(|i| {
+        if i % every != 0 { return (2 << 64) | i; }
+        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407) & 0xFFFFFFFFFFFFFFFF;
+        (1 << 64) | ((seed >> 33) % hot)
+    }).collect()
+}
+
+pub fn keeper_benchmark(c: &mut Criterion) {
+    let root = temp_dir().join("rustDB_bench_keeper");
+    let _ = remove_dir_all(&root);
+    let db = Database::open(&root).unwrap();
+    create_file(&db, "1").unwrap();
+    create_file(&db, "2").unwrap();
+    for _ in 0..48 {
+        append_block(&db, "1").unwrap();
+    }
+    for _ in 0..2000 {
+        append_block(&db, "2").unwrap();
+    }
+    for (name, trace) in [("1 in 2", scan_and_lookups(2000, 48, 2)), ("1 in 4", scan_and_lookups(2000, 48, 4)), ("1 in 16", scan_and_lookups(2000, 48, 16))] {
+        for policy in [Policy::Clock, Policy::Lru, Policy::LruK(2), Policy::TwoQ] {
+            c.bench_function(&format!("keeper {policy:?}, lookup {name}"), |b| b.iter(|| {
+                let buf = PageBuffer::with_policy(Arc::clone(&db), 64, policy);
+                for page_id in &trace {
+                    buf.fetch(*page_id).unwrap();
+                }
+                buf.stats().hit_ratio()
+            }));
+        }
+    }
+    remove_dir_all(&root).expect("Could not delete benchmark directory");
+}
+
+/// `threads` threads fetching resident pages at random, as concurrent queries over a warm pool do.
+/// With a single shard every hit queues on the same keeper; with more they mostly don't.
+pub fn sharded_fetch_benchmark(c: &mut Criterion) {
+    let root = temp_dir().join("rustDB_bench_sharded_fetch");
+    let _ = remove_dir_all(&root);
+    let db = Database::open(&root).unwrap();
+    create_file(&db, "1").unwrap();
+    for _ in 0..256 {
+        append_block(&db, "1").unwrap();
+    }
+    let threads = thread::available_parallelism().map_or(4, |n| n.get()).max(4);
+    for shards in [1, 8] {
+        let buf = PageBuffer::with_shards(Arc::clone(&db), 512, shards, Policy::Clock);
+        for b in 0..256 {
+            buf.fetch((1 << 64) | b).unwrap();
+        }
+        c.bench_function(&format!("fetch, {threads} threads, {shards} shards"), |b| b.iter_custom(|iters| {
+            let start = Instant::now();
+            thread::scope(|s| for t in 0..threads {
+                let buf = &buf;
+                s.spawn(move || {
+                    let mut seed = t as u128 + 1;
+                    for _ in 0..iters * 1000 {
+                        seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407) & 0xFFFFFFFFFFFFFFFF;
+                        buf.fetch((1 << 64) | ((seed >> 33) % 256)).unwrap();
+                    }
+                });
+            });
+            start.elapsed()
+        }));
+    }
+    remove_dir_all(&root).expect("Could not delete benchmark directory");
+}
+
+criterion_group!{
+    name = sharded;
+    config = Criterion::default().measurement_time(Duration::from_secs(3));
+    targets = sharded_fetch_benchmark
+}
+criterion_group!(benches, block_read_benchmark, seq_scan_benchmark, keeper_benchmark);
+criterion_main!(benches, sharded);
\ No newline at end of file
@@ -0,0 +1,285 @@
+use std::collections::{HashMap, VecDeque};
+
+use super::{Keeper, Clock};
+
+/// Replacement policy of a `PageBuffer`, chosen when the pool is constructed.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Policy {
+    Clock,
+    Lru,
+    /// Evicts the page whose k-th most recent access is oldest.
+    LruK(usize),
+    TwoQ
+}
+
+impl Policy {
+    pub fn keeper(self, size: usize) -> Box<dyn Keeper + Send> {
+        match self {
+            Self::Clock => Box::new(Clock::new(size)),
+            Self::Lru => Box::new(Lru::new(size)),
+            Self::LruK(k) => Box::new(LruK::new(size, k)),
+            Self::TwoQ => Box::new(TwoQ::new(size))
+        }
+    }
+}
+
+impl Keeper for Box<dyn Keeper + Send> {
+    fn add_hook(&mut self, idx: usize, page_id: u128) {
+        (**self).add_hook(idx, page_id)
+    }
+
+    fn fetch_hook(&mut self, idx: usize) {
+        (**self).fetch_hook(idx)
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (**self).evict(pinned)
+    }
+
+    fn resize(&mut self, len: usize) {
+        (**self).resize(len)
+    }
+}
+
+pub struct Lru {
+    tick: u64,
+    last: Vec<u64>
+}
+
+impl Lru {
+    pub fn new(size: usize) -> Self {
+        Self { tick: 0, last: vec![0; size] }
+    }
+}
+
+impl Keeper for Lru {
+    fn add_hook(&mut self, idx: usize, _page_id: u128) {
+        self.fetch_hook(idx);
+    }
+
+    fn fetch_hook(&mut self, idx: usize) {
+        self.tick += 1;
+        self.last[idx] = self.tick;
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        (0..self.last.len()).filter(|i| !pinned(*i)).min_by_key(|i| self.last[*i])
+    }
+
+    fn resize(&mut self, len: usize) {
+        self.last.resize(len, 0);
+    }
+}
+
+/// Keeps the last `k` access times of every resident page, and of as many recently evicted
+/// ones, so a page read back soon after eviction does not start over as a one-off.
+pub struct LruK {
+    k: usize,
+    tick: u64,
+    history: Vec<VecDeque<u64>>,
+    pages: Vec<Option<u128>>,
+    evicted: HashMap<u128, VecDeque<u64>>
+}
+
+impl LruK {
+    pub fn new(size: usize, k: usize) -> Self {
+        Self { k: k.max(1), tick: 0, history: vec![VecDeque::new(); size], pages: vec![None; size], evicted: HashMap::new() }
+    }
+
+    fn access(&mut self, idx: usize) {
+        self.tick += 1;
+        let history = &mut self.history[idx];
+        history.push_back(self.tick);
+        if history.len() > self.k { history.pop_front(); }
+    }
+
+    /// Pages seen fewer than `k` times have an infinite backward distance and go first, least recent
+    /// among them; the rest go by the age of their k-th most recent access.
+    fn victim_key(&self, idx: usize) -> (bool, u64) {
+        let history = &self.history[idx];
+        if history.len() < self.k {
+            (false, history.back().copied().unwrap_or(0))
+        } else {
+            (true, history[0])
+        }
+    }
+}
+
+impl Keeper for LruK {
+    fn add_hook(&mut self, idx: usize, page_id: u128) {
+        self.history[idx] = self.evicted.remove(&page_id).unwrap_or_default();
+        self.pages[idx] = Some(page_id);
+        self.access(idx);
+    }
+
+    fn fetch_hook(&mut self, idx: usize) {
+        self.access(idx);
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        let victim = (0..self.history.len()).filter(|i| !pinned(*i)).min_by_key(|i| self.victim_key(*i))?;
+        if let Some(page_id) = self.pages[victim].take() {
+            self.evicted.insert(page_id, std::mem::take(&mut self.history[victim]));
+            if self.evicted.len() > self.history.len() {
+                let oldest = self.evicted.iter().min_by_key(|(_, h)| h.back().copied()).map(|(id, _)| *id).unwrap();
+                self.evicted.remove(&oldest);
+            }
+        }
+        self.history[victim].clear();
+        Some(victim)
+    }
+
+    fn resize(&mut self, len: usize) {
+        self.history.resize(len, VecDeque::new());
+        self.pages.resize(len, None);
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Queue {
+    Free,
+    /// First-time pages, evicted in the order they came in.
+    In(u64),
+    /// Pages referenced more than once, evicted least recently used first.
+    Main(u64)
+}
+
+/// 2Q: new pages wait in a small FIFO and are promoted to the main LRU queue only when referenced
+/// again, either while still in it or soon after leaving it, as remembered by the ghost queue.
+/// A scan therefore cycles through the FIFO without displacing the hot pages.
+pub struct TwoQ {
+    tick: u64,
+    queues: Vec<Queue>,
+    pages: Vec<Option<u128>>,
+    ghosts: VecDeque<u128>,
+    max_in: usize,
+    max_ghosts: usize
+}
+
+impl TwoQ {
+    pub fn new(size: usize) -> Self {
+        Self { tick: 0, queues: vec![Queue::Free; size], pages: vec![None; size], ghosts: VecDeque::new(), max_in: (size / 4).max(1), max_ghosts: (size / 2).max(1) }
+    }
+
+    fn oldest(&self, pinned: &dyn Fn(usize) -> bool, main: bool) -> Option<usize> {
+        (0..self.queues.len()).filter(|i| !pinned(*i)).filter_map(|i| match self.queues[i] {
+            Queue::In(t) if !main => Some((t, i)),
+            Queue::Main(t) if main => Some((t, i)),
+            _ => None
+        }).min().map(|(_, i)| i)
+    }
+}
+
+impl Keeper for TwoQ {
+    fn add_hook(&mut self, idx: usize, page_id: u128) {
+        self.tick += 1;
+        let seen = self.ghosts.iter().position(|id| *id == page_id);
+        self.queues[idx] = match seen {
+            Some(pos) => {
+                self.ghosts.remove(pos);
+                Queue::Main(self.tick)
+            },
+            None => Queue::In(self.tick)
+        };
+        self.pages[idx] = Some(page_id);
+    }
+
+    fn fetch_hook(&mut self, idx: usize) {
+        self.tick += 1;
+        if !matches!(self.queues[idx], Queue::Free) { self.queues[idx] = Queue::Main(self.tick); }
+    }
+
+    fn evict(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        if let Some(free) = (0..self.queues.len()).find(|i| !pinned(*i) && matches!(self.queues[*i], Queue::Free)) {
+            return Some(free);
+        }
+        let resident_in = self.queues.iter().filter(|q| matches!(q, Queue::In(_))).count();
+        let from_main = resident_in <= self.max_in;
+        let victim = self.oldest(pinned, from_main).or_else(|| self.oldest(pinned, !from_main))?;
+        if let (Queue::In(_), Some(page_id)) = (self.queues[victim], self.pages[victim]) {
+            self.ghosts.push_back(page_id);
+            if self.ghosts.len() > self.max_ghosts { self.ghosts.pop_front(); }
+        }
+        self.queues[victim] = Queue::Free;
+        self.pages[victim] = None;
+        Some(victim)
+    }
+
+    fn resize(&mut self, len: usize) {
+        self.queues.resize(len, Queue::Free);
+        self.pages.resize(len, None);
+        self.max_in = (len / 4).max(1);
+        self.max_ghosts = (len / 2).max(1);
+        while self.ghosts.len() > self.max_ghosts { self.ghosts.pop_front(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::buffer::Keeper;
+
+    use super::Policy;
+
+    /// Replays `trace` against a pool of `size` frames run by the policy and returns the number of hits.
+    fn hits(policy: Policy, size: usize, trace: &[u128]) -> usize {
+        let mut keeper = policy.keeper(size);
+        let mut frames = HashMap::new();
+        let mut resident = vec![None; size];
+        let mut hits = 0;
+        for page_id in trace {
+            if let Some(idx) = frames.get(page_id) {
+                keeper.fetch_hook(*idx);
+                hits += 1;
+                continue;
+            }
+            let idx = keeper.evict(&|_| false).unwrap();
+            if let Some(old) = resident[idx].replace(*page_id) { frames.remove(&old); }
+            frames.insert(*page_id, idx);
+            keeper.add_hook(idx, *page_id);
+        }
+        hits
+    }
+
+    #[test]
+    fn test_pinned_never_evicted() {
+        for policy in [Policy::Clock, Policy::Lru, Policy::LruK(2), Policy::TwoQ] {
+            let mut keeper = policy.keeper(3);
+            for i in 0..3 {
+                let idx = keeper.evict(&|_| false).unwrap();
+                keeper.add_hook(idx, i);
+            }
+            for _ in 0..5 {
+                assert_ne!(keeper.evict(&|i| i == 1), Some(1), "{policy:?}");
+            }
+            assert_eq!(keeper.evict(&|_| true), None, "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn test_resize() {
+        for policy in [Policy::Clock, Policy::Lru, Policy::LruK(2), Policy::TwoQ] {
+            let mut keeper = policy.keeper(2);
+            for i in 0..2 {
+                let idx = keeper.evict(&|_| false).unwrap();
+                keeper.add_hook(idx, i);
+            }
+            keeper.resize(4);
+            assert!(keeper.evict(&|_| false).unwrap() >= 2, "{policy:?}");
+            keeper.resize(1);
+            assert_eq!(keeper.evict(&|_| false), Some(0), "{policy:?}");
+        }
+    }
+
+    #[test]
+    fn test_scan_resistance() {
+        // a hot set of 4 pages, touched twice up front and then once every 10 pages of a long scan
+        let mut trace: Vec<u128> = (0..8).map(|i| i % 4).collect();
+        trace.extend((0..2000).map(|i| if i % 10 == 0 { (i / 10) % 4 } else { 100 + i }));
+        let lru = hits(Policy::Lru, 16, &trace);
+        assert!(lru < 10);
+        assert!(hits(Policy::LruK(2), 16, &trace) > 150);
+        assert!(hits(Policy::TwoQ, 16, &trace) > 150);
+    }
+}
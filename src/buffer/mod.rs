@@ -1,69 +1,306 @@
 #![allow(dead_code)]
 
-use std::{sync::{RwLock, Mutex}, marker::PhantomData, slice::Iter};
+use std::{sync::{RwLock, Mutex, Condvar, Arc, atomic::{AtomicBool, AtomicUsize, Ordering}}, marker::PhantomData, slice::Iter, collections::{HashMap, HashSet}, ops::{Deref, Range}};
 
-use crate::storage::{disk_manager::{self, write_block}, folder::HeadBuffer};
+use crate::{storage::{disk_manager::{self, write_block, SET_64}, folder::HeadBuffer, database::Database}, error::Error};
 
 pub mod page;
 pub mod tuple;
+pub mod keepers;
+pub mod writer;
+pub mod stats;
+pub mod strategy;
+pub mod read_ahead;
 use page::*;
 
-use self::tuple::Table;
+use self::{tuple::Table, keepers::Policy, stats::{Counters, BufferStats}, strategy::{Strategy, Ring, RING_FRAMES}, read_ahead::READ_AHEAD};
 
 pub trait BuffInner<T> {
     type Item;
     fn add(&self, idx: usize,  item: Self::Item) -> &T;
-    fn remove(&self, idx: usize);
+    fn remove(&self, db: &Database, idx: usize) -> Result<(), Error>;
+    fn get(&self, idx: usize) -> &T;
     fn iter(&self) -> Iter<'_, T>;
 }
 
 pub trait Buff<T> {
     type Item;
-    fn admit(&self, page: Self::Item) -> &T;
-    fn evict(&self) -> usize;
-    fn fetch(&self, page_id: u128) -> &T;
-    fn flush(&self);
+    fn admit(&self, page: Self::Item) -> Result<FrameGuard<'_, T>, Error>;
+    fn evict(&self) -> Result<usize, Error>;
+    fn fetch_with(&self, page_id: u128, strategy: &Strategy) -> Result<FrameGuard<'_, T>, Error>;
+    fn flush(&self) -> Result<(), Error>;
+
+    fn fetch(&self, page_id: u128) -> Result<FrameGuard<'_, T>, Error> {
+        self.fetch_with(page_id, &Strategy::Normal)
+    }
+}
+
+/// A pinned frame. The frame cannot be evicted until every guard on it is dropped.
+pub struct FrameGuard<'a, T> {
+    frame: &'a T,
+    pin: &'a AtomicUsize
 }
 
+pub type PageGuard<'a> = FrameGuard<'a, RwLock<Page>>;
+
+impl<T> Deref for FrameGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.frame
+    }
+}
+
+impl<T> Drop for FrameGuard<'_, T> {
+    fn drop(&mut self) {
+        self.pin.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Frames are allocated up to the capacity the pool is built with, of which `size` are in use
+/// at a time; frames never move, so guards stay valid while the pool is resized.
+/// The frames are split into shards, each with its own keeper, page table and admission lock,
+/// and every page id hashes to one of them. Within a shard, `table` maps resident page ids to
+/// their frame. Hits only take its read lock and leave the keeper alone when someone else holds
+/// it. A miss claims its page id in `io` and reads the block without holding any lock, so misses
+/// of different pages proceed in parallel; a fetch of a page that is being read, or whose evicted
+/// copy is being written back, waits for that to finish. Misses hold `admission` shared while
+/// they evict and place, and resizing and flushing hold it exclusively.
 pub struct Buffer<T, U: BuffInner<T>,  V: Keeper> {
     _marker: PhantomData<T>,
     inner: U,
+    shards: Vec<Shard<V>>,
+    pins: Vec<AtomicUsize>,
+    db: Arc<Database>
+}
+
+/// Frames `start..start + cap` of a pool, of which the first `len` are in use, with the counters
+/// of the pages hashed to them. The keeper sees the frames in use as `0..len`.
+struct Shard<V> {
+    start: usize,
+    cap: usize,
+    len: AtomicUsize,
     keeper: Mutex<V>,
-    size: usize
+    /// Frames hit while the keeper was busy, told to it before it next picks a victim.
+    hinted: Vec<AtomicBool>,
+    table: RwLock<HashMap<u128, usize>>,
+    admission: RwLock<()>,
+    /// Pages being read in or written back, and the signal that one of them is done.
+    io: Mutex<HashSet<u128>>,
+    io_done: Condvar,
+    counters: Counters
 }
 
-impl Buffer<RwLock<Page>, BufferInner<RwLock<Page>>, Clock> {
-    pub fn new(size: usize) -> Self {
-        Self { _marker: PhantomData, inner: BufferInner::<RwLock<Page>>::new(size), keeper: Mutex::new(Clock::new(size)), size }
+impl<V> Shard<V> {
+    /// Splits `capacity` frames into `shards` shards as evenly as possible, `size` of them in use.
+    fn split(size: usize, capacity: usize, shards: usize, keeper: impl Fn(usize) -> V) -> Vec<Self> {
+        let shards = shards.clamp(1, capacity.max(1));
+        let caps: Vec<_> = (0..shards).map(|i| (i + 1) * capacity / shards - i * capacity / shards).collect();
+        caps.iter().zip(spread(size, &caps)).enumerate().map(|(i, (cap, len))| {
+            Self { start: i * capacity / shards, cap: *cap, len: AtomicUsize::new(len), keeper: Mutex::new(keeper(len)), hinted: (0..*cap).map(|_| AtomicBool::new(false)).collect(), table: RwLock::new(HashMap::with_capacity(*cap)), admission: RwLock::new(()), io: Mutex::new(HashSet::new()), io_done: Condvar::new(), counters: Counters::default() }
+        }).collect()
+    }
+
+    fn len(&self) -> usize {
+        self.len.load(Ordering::SeqCst)
+    }
+
+    /// The frames in use.
+    fn frames(&self) -> Range<usize> {
+        self.start..self.start + self.len()
+    }
+
+    /// Tells the keeper frame `idx` was hit, or leaves a hint for it if the keeper is busy.
+    fn touch(&self, idx: usize) where V: Keeper {
+        match self.keeper.try_lock() {
+            Ok(mut keeper) => keeper.fetch_hook(idx - self.start),
+            Err(_) => self.hinted[idx - self.start].store(true, Ordering::SeqCst)
+        }
+    }
+
+    /// Claims `page_id` for reading it in. Fails if the page is resident or was being read or
+    /// written back, after waiting for that to finish.
+    fn claim(&self, page_id: u128) -> bool {
+        let io = self.io.lock().unwrap();
+        if io.contains(&page_id) {
+            drop(self.io_done.wait_while(io, |io| io.contains(&page_id)).unwrap());
+            return false;
+        }
+        if self.table.read().unwrap().contains_key(&page_id) { return false; }
+        let mut io = io;
+        io.insert(page_id);
+        true
+    }
+
+    /// Lets the fetches waiting for `page_id` go on.
+    fn unclaim(&self, page_id: u128) {
+        self.io.lock().unwrap().remove(&page_id);
+        self.io_done.notify_all();
+    }
+}
+
+/// Hands out `size` frames to shards of the given capacities round robin, at least one each.
+fn spread(size: usize, caps: &[usize]) -> Vec<usize> {
+    let mut lens = vec![0; caps.len()];
+    let mut left = size.max(caps.len());
+    while left > 0 && lens.iter().zip(caps).any(|(len, cap)| len < cap) {
+        for (len, cap) in lens.iter_mut().zip(caps) {
+            if left > 0 && *len < *cap { *len += 1; left -= 1; }
+        }
+    }
+    lens
+}
+
+impl Buffer<RwLock<Page>, BufferInner<RwLock<Page>>, Box<dyn Keeper + Send>> {
+    pub fn new(db: Arc<Database>, size: usize) -> Self {
+        Self::with_policy(db, size, Policy::Clock)
+    }
+
+    pub fn with_policy(db: Arc<Database>, size: usize, policy: Policy) -> Self {
+        Self::with_shards(db, size, 1, policy)
+    }
+
+    /// A pool of `size` frames split into `shards` independent shards, each run by its own
+    /// keeper of the given policy. There are never more shards than frames.
+    pub fn with_shards(db: Arc<Database>, size: usize, shards: usize, policy: Policy) -> Self {
+        Self::with_capacity(db, size, size, shards, policy)
+    }
+
+    /// Like `with_shards`, with room to grow to `capacity` frames through `resize`.
+    pub fn with_capacity(db: Arc<Database>, size: usize, capacity: usize, shards: usize, policy: Policy) -> Self {
+        let capacity = capacity.max(size);
+        Self { _marker: PhantomData, inner: BufferInner::<RwLock<Page>>::new(capacity), shards: Shard::split(size, capacity, shards, |len| policy.keeper(len)), pins: (0..capacity).map(|_| AtomicUsize::new(0)).collect(), db }
+    }
+}
+
+impl<T, U: BuffInner<T>, V: Keeper> Buffer<T, U, V> {
+    /// The shard `page_id` belongs to; consecutive blocks of a file are spread across shards.
+    fn shard(&self, page_id: u128) -> &Shard<V> {
+        &self.shards[self.shard_index(page_id)]
+    }
+
+    fn shard_index(&self, page_id: u128) -> usize {
+        let h = ((page_id >> 64) as u64 ^ page_id as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+        (h >> 32) as usize % self.shards.len()
+    }
+
+    /// The frame the shard's keeper picks, never a pinned one.
+    fn victim(&self, shard: &Shard<V>) -> Result<usize, Error> {
+        let mut keeper = shard.keeper.lock().unwrap();
+        for i in 0..shard.len() {
+            if shard.hinted[i].swap(false, Ordering::SeqCst) { keeper.fetch_hook(i); }
+        }
+        let local = keeper.evict(&|i| self.pins[shard.start + i].load(Ordering::SeqCst) > 0).ok_or(Error::BufferFull)?;
+        Ok(shard.start + local)
+    }
+
+    /// Tells the shard's keeper frame `idx` now holds `page_id`, dropping any hint left for the
+    /// page it held before.
+    fn added(&self, shard: &Shard<V>, idx: usize, page_id: u128) {
+        shard.hinted[idx - shard.start].store(false, Ordering::SeqCst);
+        shard.keeper.lock().unwrap().add_hook(idx - shard.start, page_id);
+    }
+
+    pub fn shard_count(&self) -> usize {
+        self.shards.len()
+    }
+
+    /// Frames in use.
+    pub fn size(&self) -> usize {
+        self.shards.iter().map(|s| s.len()).sum()
+    }
+
+    /// Frames the pool can grow to.
+    pub fn capacity(&self) -> usize {
+        self.pins.len()
     }
 }
 
 impl HeadBuffer {
-    pub fn new(size: usize) -> Self {
-        Self { _marker: PhantomData, inner: BufferInner::<RwLock<Option<Box<dyn Table + Send + Sync>>>>::new(size), keeper: Mutex::new(Clock::new(size)), size}
+    pub fn new(db: Arc<Database>, size: usize) -> Self {
+        Self { _marker: PhantomData, inner: BufferInner::<RwLock<Option<Box<dyn Table + Send + Sync>>>>::new(size), shards: Shard::split(size, size, 1, Clock::new), pins: (0..size).map(|_| AtomicUsize::new(0)).collect(), db }
+    }
+
+    /// The cached header of the table whose header file is `head_ino`, read with `load` on a miss.
+    pub fn fetch_with(&self, head_ino: u64, load: impl FnOnce() -> Result<Box<dyn Table + Send + Sync>, Error>) -> Result<FrameGuard<'_, HeadFrame>, Error> {
+        let shard = self.shard(head_ino as u128);
+        Counters::bump(&shard.counters.fetches);
+        if let Some(frame) = self.lookup(head_ino) { return Ok(frame); }
+        let _admission = shard.admission.write().unwrap();
+        if let Some(frame) = self.lookup(head_ino) { return Ok(frame); }
+        Counters::bump(&shard.counters.misses);
+        let table = load()?;
+        let idx = self.evict(shard)?;
+        let frame = self.inner.add(idx, Some(table));
+        shard.table.write().unwrap().insert(head_ino as u128, idx);
+        self.added(shard, idx, head_ino as u128);
+        Ok(FrameGuard { frame, pin: &self.pins[idx] })
+    }
+
+    /// Forgets the cached header, for tables that are dropped or rewritten outside of the cache.
+    pub fn invalidate(&self, head_ino: u64) {
+        let shard = self.shard(head_ino as u128);
+        let _admission = shard.admission.write().unwrap();
+        let Some(idx) = shard.table.write().unwrap().remove(&(head_ino as u128)) else { return; };
+        let mut t = self.inner.get(idx).write().unwrap();
+        if t.as_ref().is_some_and(|t| t.inode().head_ino == head_ino) { *t = None; }
+    }
+
+    fn lookup(&self, head_ino: u64) -> Option<FrameGuard<'_, HeadFrame>> {
+        let shard = self.shard(head_ino as u128);
+        let idx = *shard.table.read().unwrap().get(&(head_ino as u128))?;
+        self.pins[idx].fetch_add(1, Ordering::SeqCst);
+        let frame = FrameGuard { frame: self.inner.get(idx), pin: &self.pins[idx] };
+        if frame.read().unwrap().as_ref().map(|t| t.inode().head_ino) != Some(head_ino) { return None; }
+        shard.touch(idx);
+        Counters::bump(&shard.counters.hits);
+        Some(frame)
+    }
+
+    /// Empties an unpinned frame; headers are saved as they change so nothing is written here.
+    fn evict(&self, shard: &Shard<Clock>) -> Result<usize, Error> {
+        loop {
+            let i = self.victim(shard)?;
+            let mut t = self.inner.get(i).write().unwrap();
+            if self.pins[i].compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst).is_err() { continue; }
+            if let Some(old) = t.take() {
+                shard.table.write().unwrap().remove(&(old.inode().head_ino as u128));
+                Counters::bump(&shard.counters.evictions);
+            }
+            return Ok(i);
+        }
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        BufferStats::from(&self.shards[0].counters)
     }
 }
 
+pub type HeadFrame = RwLock<Option<Box<dyn Table + Send + Sync>>>;
+
 pub struct BufferInner<T> {
     data: Vec<T>
 }
 
 impl BufferInner<RwLock<Page>> {
     fn new(size: usize) -> Self {
-        Self { data: (0..size).into_iter().map(|_| RwLock::new(Page::new())).collect() }
+        Self { data: (0..size).map(|_| RwLock::new(Page::new())).collect() }
     }
 }
 
 impl BufferInner<RwLock<Option<Box<dyn Table + Send + Sync>>>> {
     fn new(size: usize) -> Self {
-        Self { data: (0..size).into_iter().map(|_| RwLock::new(None)).collect() }
+        Self { data: (0..size).map(|_| RwLock::new(None)).collect() }
     }
 }
 
 pub trait Keeper {
-    fn add_hook(&mut self, idx: usize);
+    fn add_hook(&mut self, idx: usize, page_id: u128);
     fn fetch_hook(&mut self, idx: usize);
-    fn evict(&mut self) -> usize; 
+    /// Picks the frame to reuse, never one for which `pinned` holds.
+    fn evict(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize>;
+    /// Takes over frames `0..len`; frames added are empty, frames dropped were emptied first.
+    fn resize(&mut self, len: usize);
 }
 
 pub struct Clock {
@@ -72,13 +309,13 @@ pub struct Clock {
 }
 
 impl Clock {
-    fn new(size: usize) -> Self {
+    pub fn new(size: usize) -> Self {
         Self { hand: 0, vis: vec![false; size] }
     }
 }
 
 impl Keeper for Clock {
-    fn add_hook(&mut self, idx: usize) {
+    fn add_hook(&mut self, idx: usize, _page_id: u128) {
         self.vis[idx] = true;
     }
 
@@ -86,16 +323,23 @@ impl Keeper for Clock {
         self.vis[idx] = true;
     }
 
-    fn evict(&mut self) -> usize {
-        for i in (self.hand..self.vis.len()).into_iter().chain((0..self.hand).into_iter()).cycle(){
+    fn evict(&mut self, pinned: &dyn Fn(usize) -> bool) -> Option<usize> {
+        // the first sweep clears every reference bit, so the second finds a victim unless all frames are pinned
+        for i in (self.hand..self.vis.len()).chain(0..self.hand).cycle().take(2 * self.vis.len()) {
+            if pinned(i) { continue; }
             if !self.vis[i] {
                 self.vis[i] = true; 
                 self.hand = i;
-                break;
+                return Some(i);
             }
             else {self.vis[i] = false;}
         };
-        self.hand
+        None
+    }
+
+    fn resize(&mut self, len: usize) {
+        self.vis.resize(len, false);
+        if self.hand >= len { self.hand = 0; }
     }
 }
 
@@ -103,38 +347,307 @@ impl<U: BuffInner<RwLock<Page>, Item = Page>, V: Keeper> Buff<RwLock<Page>> for
 
     type Item = Page;
 
-    fn admit(&self, page: Page) -> &RwLock<Page> {
-        let target_idx = self.evict();
-        let res = self.inner.add(target_idx, page);
-        let mut keeper = self.keeper.lock().unwrap();
-        keeper.add_hook(target_idx);
+    fn admit(&self, page: Page) -> Result<PageGuard<'_>, Error> {
+        let target_idx = match page.page_id {
+            Some(page_id) => self.evict_from(self.shard(page_id))?,
+            None => self.evict()?
+        };
+        Ok(self.place(target_idx, page))
+    }
+
+    /// Empties an unpinned frame of the first shard that has one and returns its index, pinned
+    /// once for the page about to be admitted.
+    fn evict(&self) -> Result<usize, Error> {
+        for shard in &self.shards {
+            match self.evict_from(shard) {
+                Err(Error::BufferFull) => continue,
+                res => return res
+            }
+        }
+        Err(Error::BufferFull)
+    }
+
+    fn fetch_with(&self, p_id: u128, strategy: &Strategy) -> Result<PageGuard<'_>, Error> {
+        let shard = self.shard(p_id);
+        Counters::bump(&shard.counters.fetches);
+        loop {
+            if let Some(frame) = self.lookup(p_id) { return Ok(frame); }
+            if shard.claim(p_id) { break; }
+        }
+        let res = self.load(shard, p_id, strategy);
+        shard.unclaim(p_id);
         res
     }
 
-    fn evict(&self) -> usize {
-        let mut keeper = self.keeper.lock().unwrap();
-        let i = keeper.evict();
-        self.inner.remove(i); 
-        i
+    /// Writes back every dirty page; pinned pages stay resident.
+    fn flush(&self) -> Result<(), Error> {
+        for shard in &self.shards {
+            let _admission = shard.admission.write().unwrap();
+            for i in shard.frames() {
+                let mut p = self.inner.get(i).write().unwrap();
+                self.write_back(shard, &mut p)?;
+                if self.pins[i].load(Ordering::SeqCst) > 0 { continue; }
+                if let Some(page_id) = p.page_id.take() { shard.table.write().unwrap().remove(&page_id); }
+            }
+        }
+        Ok(())
     }
+}
 
-    fn fetch(&self, p_id: u128) -> &RwLock<Page> {
-        if let Some((idx, val)) = self.inner.iter().enumerate().find(|(_, p)| p.read().unwrap().page_id == Some(p_id)) {
-            let mut keeper = self.keeper.lock().unwrap();
-            keeper.fetch_hook(idx);
-            return val;
+impl<U: BuffInner<RwLock<Page>, Item = Page>, V: Keeper> Buffer<RwLock<Page>, U, V> {
+    /// Reads in a page the caller claimed and places it through `strategy`.
+    fn load(&self, shard: &Shard<V>, p_id: u128, strategy: &Strategy) -> Result<PageGuard<'_>, Error> {
+        Counters::bump(&shard.counters.misses);
+        let block = disk_manager::read_block(&self.db, p_id)?;
+        let page = Page { page_id: Some(p_id), block: Some(block) };
+        let _admission = shard.admission.read().unwrap();
+        match strategy {
+            Strategy::Normal => self.admit(page),
+            Strategy::Ring(ring) => {
+                let target_idx = self.evict_ring(shard, ring)?;
+                ring.push(target_idx, p_id);
+                Ok(self.place(target_idx, page))
+            }
         }
-        let block = disk_manager::read_block(p_id);
-        self.admit(
-            Page { page_id: Some(p_id), block: Some(block) }
-        )
     }
 
-    fn flush(&self) {
-        for i in 0..self.size {
-            self.inner.remove(i);
-        };
+    /// Puts the page into a frame `evict` emptied and pinned.
+    fn place(&self, idx: usize, page: Page) -> PageGuard<'_> {
+        let page_id = page.page_id;
+        let res = self.inner.add(idx, page);
+        let shard = self.shards.iter().find(|s| (s.start..s.start + s.cap).contains(&idx)).unwrap();
+        if let Some(page_id) = page_id {
+            shard.table.write().unwrap().insert(page_id, idx);
+        }
+        self.added(shard, idx, page_id.unwrap_or_default());
+        FrameGuard { frame: res, pin: &self.pins[idx] }
+    }
+
+    fn evict_from(&self, shard: &Shard<V>) -> Result<usize, Error> {
+        loop {
+            let i = self.victim(shard)?;
+            if self.reclaim(shard, i, None)? { return Ok(i); }
+        }
+    }
+
+    /// Empties frame `i` of `shard` and pins it once, provided nobody holds it and, if `expected`
+    /// is given, it still holds that page. Returns whether the frame was taken.
+    fn reclaim(&self, shard: &Shard<V>, i: usize, expected: Option<u128>) -> Result<bool, Error> {
+        let mut p = self.inner.get(i).write().unwrap();
+        if expected.is_some_and(|id| p.page_id != Some(id)) { return Ok(false); }
+        // a lookup may have pinned the frame after it was chosen; lookups check the
+        // page id under the frame lock, so once this succeeds none of them can return it
+        if self.pins[i].compare_exchange(0, 1, Ordering::SeqCst, Ordering::SeqCst).is_err() { return Ok(false); }
+        // the page is marked as in flight before it leaves the table, so a fetch waits for the
+        // write back instead of reading the stale block
+        let page_id = p.page_id;
+        if let Some(page_id) = page_id {
+            shard.io.lock().unwrap().insert(page_id);
+            shard.table.write().unwrap().remove(&page_id);
+        }
+        let res = self.write_back(shard, &mut p);
+        if res.is_err() {
+            if let Some(page_id) = page_id { shard.table.write().unwrap().insert(page_id, i); }
+            self.pins[i].fetch_sub(1, Ordering::SeqCst);
+        } else if p.page_id.take().is_some() {
+            Counters::bump(&shard.counters.evictions);
+        }
+        drop(p);
+        if let Some(page_id) = page_id { shard.unclaim(page_id); }
+        res.map(|_| true)
+    }
+
+    /// Reuses the oldest frame the ring holds in `shard` once it is full there, falling back to
+    /// the keeper while the ring fills up or when the main pool has taken its frame back.
+    fn evict_ring(&self, shard: &Shard<V>, ring: &Ring) -> Result<usize, Error> {
+        if let Some((idx, page_id)) = ring.reusable(shard.frames()) {
+            if self.reclaim(shard, idx, Some(page_id))? { return Ok(idx); }
+        }
+        self.evict_from(shard)
     }
+
+    /// The strategy for a scan over `blocks` blocks: tables larger than a quarter of the pool
+    /// go through a ring so a single scan cannot wipe out everyone else's pages.
+    pub fn scan_strategy(&self, blocks: u64) -> Strategy {
+        if blocks as usize <= self.size() / 4 { return Strategy::Normal; }
+        self.build_strategy()
+    }
+
+    /// The strategy for building a temporary hash table, whose size is not known up front.
+    pub fn build_strategy(&self) -> Strategy {
+        Strategy::ring((self.size() / 8).min(RING_FRAMES) / self.shards.len())
+    }
+
+    /// Grows or shrinks the pool to `size` frames, at least one per shard and at most its
+    /// capacity, and returns the size it ends up with. Frames given up are written back and
+    /// emptied first. A pinned frame is never given up, so a shard stops shrinking at the first
+    /// one from the top and the pool may stay larger than asked.
+    pub fn resize(&self, size: usize) -> Result<usize, Error> {
+        let caps: Vec<usize> = self.shards.iter().map(|s| s.cap).collect();
+        for (shard, len) in self.shards.iter().zip(spread(size, &caps)) {
+            let _admission = shard.admission.write().unwrap();
+            let mut len = len;
+            let mut taken = vec![];
+            for i in (shard.start + len..shard.start + shard.len()).rev() {
+                match self.reclaim(shard, i, None) {
+                    Ok(true) => taken.push(i),
+                    Ok(false) => { len = i + 1 - shard.start; break; },
+                    Err(e) => {
+                        taken.iter().for_each(|i| { self.pins[*i].fetch_sub(1, Ordering::SeqCst); });
+                        return Err(e);
+                    }
+                }
+            }
+            let mut keeper = shard.keeper.lock().unwrap();
+            shard.len.store(len, Ordering::SeqCst);
+            keeper.resize(len);
+            drop(keeper);
+            // the frames are out of reach now, so nobody can pin them until the shard grows again
+            taken.iter().for_each(|i| { self.pins[*i].fetch_sub(1, Ordering::SeqCst); });
+        }
+        Ok(self.size())
+    }
+
+    /// Blocks a scan going through `strategy` may read ahead without pushing out the pages it
+    /// read ahead before reaching them.
+    pub fn read_ahead_window(&self, strategy: &Strategy) -> usize {
+        match strategy {
+            Strategy::Normal => (self.size() / 8).min(READ_AHEAD),
+            Strategy::Ring(ring) => (ring.cap() * self.shards.len() / 2).min(READ_AHEAD)
+        }
+    }
+
+    /// Reads the `count` blocks from `page_id` on that are not resident with one read and admits
+    /// them unpinned through `strategy`, for a scan about to reach them. The blocks are claimed
+    /// like a miss claims its page, so fetches of them wait for the read instead of reading them
+    /// again; blocks someone else is reading in or writing back are skipped. Stops early at the
+    /// end of the file or when every frame of a shard is pinned. Returns the number of pages admitted.
+    pub fn prefetch(&self, page_id: u128, count: usize, strategy: &Strategy) -> Result<usize, Error> {
+        let block = (page_id & SET_64 as u128) as u64;
+        let ids: Vec<u128> = (0..count as u64).map_while(|i| block.checked_add(i)).map(|b| (page_id & !(SET_64 as u128)) | b as u128).collect();
+        let missing: Vec<u128> = ids.into_iter().filter(|id| {
+            let shard = self.shard(*id);
+            let mut io = shard.io.lock().unwrap();
+            !io.contains(id) && !shard.table.read().unwrap().contains_key(id) && io.insert(*id)
+        }).collect();
+        let res = self.read_ahead(&missing, strategy);
+        missing.iter().for_each(|id| self.shard(*id).unclaim(*id));
+        res
+    }
+
+    /// Reads the claimed blocks `missing` with one read and admits them.
+    fn read_ahead(&self, missing: &[u128], strategy: &Strategy) -> Result<usize, Error> {
+        let (Some(first), Some(last)) = (missing.first(), missing.last()) else { return Ok(0); };
+        let blocks = disk_manager::read_blocks(&self.db, *first, (last - first) as usize + 1)?;
+        let mut admitted = 0;
+        for (id, block) in (*first..).zip(blocks) {
+            if !missing.contains(&id) { continue; }
+            let shard = self.shard(id);
+            let _admission = shard.admission.read().unwrap();
+            let target_idx = match strategy {
+                Strategy::Normal => self.evict_from(shard),
+                Strategy::Ring(ring) => self.evict_ring(shard, ring)
+            };
+            let target_idx = match target_idx {
+                Err(Error::BufferFull) => break,
+                res => res?
+            };
+            if let Strategy::Ring(ring) = strategy { ring.push(target_idx, id); }
+            drop(self.place(target_idx, Page { page_id: Some(id), block: Some(block) }));
+            Counters::bump(&shard.counters.prefetched);
+            admitted += 1;
+        }
+        Ok(admitted)
+    }
+
+    fn lookup(&self, p_id: u128) -> Option<PageGuard<'_>> {
+        let shard = self.shard(p_id);
+        let idx = *shard.table.read().unwrap().get(&p_id)?;
+        self.pins[idx].fetch_add(1, Ordering::SeqCst);
+        let frame = FrameGuard { frame: self.inner.get(idx), pin: &self.pins[idx] };
+        // the frame may have been handed to another page since the table was read
+        if frame.read().unwrap().page_id != Some(p_id) { return None; }
+        shard.touch(idx);
+        Counters::bump(&shard.counters.hits);
+        Some(frame)
+    }
+
+    fn write_back(&self, shard: &Shard<V>, p: &mut Page) -> Result<bool, Error> {
+        let written = write_back(&self.db, p).inspect_err(|_| Counters::bump(&shard.counters.failed_writes))?;
+        if written { Counters::bump(&shard.counters.writes); }
+        Ok(written)
+    }
+
+    pub fn stats(&self) -> BufferStats {
+        let mut stats = BufferStats::default();
+        for shard in &self.shards {
+            stats += &shard.counters;
+            for page_id in shard.table.read().unwrap().keys() {
+                *stats.resident.entry((page_id >> 64) as u64).or_default() += 1;
+            }
+        }
+        stats
+    }
+
+    /// Writes back every dirty page without evicting it and returns how many were written.
+    pub fn write_dirty(&self) -> Result<usize, Error> {
+        let mut written = 0;
+        for shard in &self.shards {
+            for i in shard.frames() {
+                if self.write_back(shard, &mut self.inner.get(i).write().unwrap())? { written += 1; }
+            }
+        }
+        Ok(written)
+    }
+
+    /// Writes back up to `limit` dirty pages, skipping frames that are locked right now so
+    /// queries never wait on it. A page that fails to write stays dirty and the round goes on
+    /// with the next one; the last failure is returned once the round is over.
+    pub fn write_idle(&self, limit: usize) -> Result<usize, Error> {
+        let mut written = 0;
+        let mut failed = None;
+        'round: for shard in &self.shards {
+            for i in shard.frames() {
+                if written == limit { break 'round; }
+                let Ok(mut p) = self.inner.get(i).try_write() else { continue; };
+                match self.write_back(shard, &mut p) {
+                    Ok(true) => written += 1,
+                    Ok(false) => (),
+                    Err(e) => failed = Some(e)
+                }
+            }
+        }
+        failed.map_or(Ok(written), Err)
+    }
+
+    /// Empties every frame holding a page of `file_ino` without writing it back,
+    /// for files that are being deleted or truncated.
+    pub fn invalidate(&self, file_ino: u64) {
+        for shard in &self.shards {
+            let _admission = shard.admission.write().unwrap();
+            shard.table.write().unwrap().retain(|page_id, _| (page_id >> 64) as u64 != file_ino);
+            for i in shard.frames() {
+                let mut p = self.inner.get(i).write().unwrap();
+                if p.page_id.is_some_and(|id| (id >> 64) as u64 == file_ino) {
+                    p.page_id = None;
+                    if p.is_dirty() { p.toggle_dirty(); }
+                }
+            }
+        }
+    }
+}
+
+/// Writes a dirty page back after the log records it depends on and tells whether there was
+/// anything to write; on failure the page stays dirty so nothing is lost.
+fn write_back(db: &Database, p: &mut Page) -> Result<bool, Error> {
+    if p.page_id.is_none() || !p.is_dirty() { return Ok(false); }
+    db.wal().flush(p.lsn())?;
+    p.toggle_dirty();
+    if let Err(e) = write_block(db, p.page_id.unwrap(), p.block.as_ref().unwrap()) {
+        p.toggle_dirty();
+        return Err(e);
+    }
+    Ok(true)
 }
 
 impl BuffInner<RwLock<Page>> for BufferInner<RwLock<Page>> {
@@ -147,13 +660,17 @@ impl BuffInner<RwLock<Page>> for BufferInner<RwLock<Page>> {
         &self.data[idx]
     }
 
-    fn remove(&self, idx: usize) {
+    /// Writes the frame back if it is dirty and empties it; on failure the page stays
+    /// buffered and dirty so nothing is lost.
+    fn remove(&self, db: &Database, idx: usize) -> Result<(), Error> {
         let mut p = self.data[idx].write().unwrap();
-        if p.page_id.is_some() && p.is_dirty() {
-            p.toggle_dirty();
-            write_block(p.page_id.unwrap(), p.block.as_ref().unwrap());
-        }
+        write_back(db, &mut p)?;
         p.page_id = None;
+        Ok(())
+    }
+
+    fn get(&self, idx: usize) -> &RwLock<Page> {
+        &self.data[idx]
     }
 
     fn iter(&self) -> Iter<'_, RwLock<Page>> {
@@ -170,12 +687,314 @@ impl BuffInner<RwLock<Option<Box<dyn Table + Send + Sync>>>> for BufferInner<RwL
         &self.data[idx]
     }
 
-    fn remove(&self, idx: usize) {
+    fn remove(&self, _db: &Database, idx: usize) -> Result<(), Error> {
         let mut t = self.data[idx].write().unwrap();
         *t = None;
+        Ok(())
+    }
+
+    fn get(&self, idx: usize) -> &RwLock<Option<Box<dyn Table + Send + Sync>>> {
+        &self.data[idx]
     }
 
     fn iter(&self) -> Iter<'_, RwLock<Option<Box<dyn Table + Send + Sync>>>> {
         self.data.iter()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, collections::HashSet};
+
+    use crate::storage::{database::test_db, utils::{create_file, append_block, delete_file}};
+
+    use crate::error::Error;
+
+    use super::{Buff, BuffInner, tuple::PageBuffer, strategy::Strategy, keepers::Policy};
+
+    /// Every page table entry points at a frame of its shard holding that page, and no page is
+    /// resident twice.
+    fn check_tables(buf: &PageBuffer) {
+        for shard in &buf.shards {
+            let table = shard.table.read().unwrap();
+            assert!(table.len() <= shard.len());
+            for (id, idx) in table.iter() {
+                assert!(std::ptr::eq(buf.shard(*id), shard));
+                assert!(shard.frames().contains(idx));
+                assert_eq!(buf.inner.get(*idx).read().unwrap().page_id, Some(*id));
+            }
+        }
+        let resident: Vec<_> = buf.inner.iter().filter_map(|p| p.read().unwrap().page_id).collect();
+        assert_eq!(resident.iter().collect::<HashSet<_>>().len(), resident.len());
+    }
+
+    #[test]
+    fn test_page_table() {
+        let db = test_db("buffer_page_table");
+        create_file(&db, "1").unwrap();
+        for _ in 0..8 {
+            append_block(&db, "1").unwrap();
+        }
+        // every thread pins at most one frame at a time, so one of the five is always free
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 5));
+        let page_id = |b: u128| (1u128 << 64) | b;
+        assert!(std::ptr::eq(&*buf.fetch(page_id(0)).unwrap(), &*buf.fetch(page_id(0)).unwrap()));
+
+        let threads: Vec<_> = (0..4).map(|t| {
+            let buf = Arc::clone(&buf);
+            thread::spawn(move || for i in 0..200 {
+                buf.fetch(page_id((i * (t + 1)) % 8)).unwrap();
+            })
+        }).collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+
+        check_tables(&buf);
+        buf.flush().unwrap();
+        assert!(buf.shards[0].table.read().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_misses_in_parallel() {
+        let db = test_db("buffer_misses_in_parallel");
+        create_file(&db, "1").unwrap();
+        for _ in 0..4 {
+            append_block(&db, "1").unwrap();
+        }
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 4));
+        let page_id = |b: u128| (1u128 << 64) | b;
+        // page 0 is being read in by someone else, which holds up its fetches but no other miss
+        buf.shards[0].io.lock().unwrap().insert(page_id(0));
+        let waiting: Vec<_> = (0..4).map(|_| {
+            let buf = Arc::clone(&buf);
+            thread::spawn(move || buf.fetch(page_id(0)).map(|p| p.read().unwrap().page_id))
+        }).collect();
+        assert_eq!(buf.fetch(page_id(1)).unwrap().read().unwrap().page_id, Some(page_id(1)));
+        assert!(waiting.iter().all(|t| !t.is_finished()));
+        buf.shards[0].unclaim(page_id(0));
+        for t in waiting {
+            assert_eq!(t.join().unwrap().unwrap(), Some(page_id(0)));
+        }
+        // only one of the waiting fetches read the page
+        assert_eq!(buf.stats().misses, 2);
+        check_tables(&buf);
+    }
+
+    #[test]
+    fn test_hit_with_keeper_busy() {
+        let db = test_db("buffer_hit_keeper_busy");
+        create_file(&db, "1").unwrap();
+        for _ in 0..3 {
+            append_block(&db, "1").unwrap();
+        }
+        let buf = PageBuffer::new(Arc::clone(&db), 2);
+        let page_id = |b: u128| (1u128 << 64) | b;
+        buf.fetch(page_id(0)).unwrap();
+        buf.fetch(page_id(1)).unwrap();
+        let keeper = buf.shards[0].keeper.lock().unwrap();
+        assert_eq!(buf.fetch(page_id(0)).unwrap().read().unwrap().page_id, Some(page_id(0)));
+        drop(keeper);
+        // the hit is told to the keeper before it picks the next victim
+        assert!(buf.shards[0].hinted.iter().any(|h| h.load(std::sync::atomic::Ordering::SeqCst)));
+        buf.fetch(page_id(2)).unwrap();
+        assert!(buf.shards[0].hinted.iter().all(|h| !h.load(std::sync::atomic::Ordering::SeqCst)));
+        assert_eq!(buf.stats().hits, 1);
+    }
+
+    #[test]
+    fn test_pinned_frames_stay() {
+        let db = test_db("buffer_pinned_frames");
+        create_file(&db, "1").unwrap();
+        for _ in 0..3 {
+            append_block(&db, "1").unwrap();
+        }
+        let buf = PageBuffer::new(Arc::clone(&db), 2);
+        let page_id = |b: u128| (1u128 << 64) | b;
+        let first = buf.fetch(page_id(0)).unwrap();
+        let second = buf.fetch(page_id(1)).unwrap();
+        assert!(matches!(buf.fetch(page_id(2)), Err(Error::BufferFull)));
+
+        drop(second);
+        let third = buf.fetch(page_id(2)).unwrap();
+        assert_eq!(first.read().unwrap().page_id, Some(page_id(0)));
+        assert_eq!(third.read().unwrap().page_id, Some(page_id(2)));
+        drop(third);
+        buf.flush().unwrap();
+        // only the unpinned frame was emptied
+        assert_eq!(buf.shards[0].table.read().unwrap().len(), 1);
+        assert_eq!(first.read().unwrap().page_id, Some(page_id(0)));
+    }
+
+    #[test]
+    fn test_stats() {
+        let db = test_db("buffer_stats");
+        create_file(&db, "1").unwrap();
+        for _ in 0..3 {
+            append_block(&db, "1").unwrap();
+        }
+        let buf = PageBuffer::new(Arc::clone(&db), 2);
+        let page_id = |b: u128| (1u128 << 64) | b;
+        for b in [0, 1, 0, 2, 0] {
+            buf.fetch(page_id(b)).unwrap();
+        }
+        buf.fetch(page_id(1)).unwrap().write().unwrap().toggle_dirty();
+        let stats = buf.stats();
+        assert_eq!((stats.fetches, stats.hits, stats.misses, stats.evictions), (6, 2, 4, 2));
+        assert_eq!(stats.resident.get(&1), Some(&2));
+
+        buf.flush().unwrap();
+        let stats = buf.stats();
+        assert_eq!(stats.writes, 1);
+        assert!(stats.resident.is_empty());
+        assert!((stats.hit_ratio() - 2.0 / 6.0).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_write_idle_past_failure() {
+        let db = test_db("buffer_write_idle_failure");
+        for file in ["1", "2"] {
+            create_file(&db, file).unwrap();
+            append_block(&db, file).unwrap();
+        }
+        let buf = PageBuffer::new(Arc::clone(&db), 4);
+        for file in [1u128, 2] {
+            buf.fetch(file << 64).unwrap().write().unwrap().toggle_dirty();
+        }
+        delete_file(&db, "1").unwrap();
+
+        // the page of the deleted file stays dirty, the other one is still written
+        assert!(buf.write_idle(4).is_err());
+        assert!(buf.fetch(1u128 << 64).unwrap().read().unwrap().is_dirty());
+        assert!(!buf.fetch(2u128 << 64).unwrap().read().unwrap().is_dirty());
+        let stats = buf.stats();
+        assert_eq!((stats.writes, stats.failed_writes), (1, 1));
+    }
+
+    #[test]
+    fn test_ring_strategy() {
+        let db = test_db("buffer_ring_strategy");
+        create_file(&db, "1").unwrap();
+        create_file(&db, "2").unwrap();
+        for _ in 0..4 {
+            append_block(&db, "1").unwrap();
+        }
+        for _ in 0..40 {
+            append_block(&db, "2").unwrap();
+        }
+        let buf = PageBuffer::new(Arc::clone(&db), 8);
+        let page_id = |f: u128, b: u128| (f << 64) | b;
+        for b in 0..4 {
+            buf.fetch(page_id(1, b)).unwrap();
+        }
+        assert!(matches!(buf.scan_strategy(2), Strategy::Normal));
+        let ring = buf.scan_strategy(40);
+        for b in 0..40 {
+            buf.fetch_with(page_id(2, b), &ring).unwrap();
+        }
+
+        // the scan cycled through its own frames and left the hot pages alone
+        let stats = buf.stats();
+        assert_eq!(stats.resident.get(&1), Some(&4));
+        assert_eq!(stats.resident.get(&2), Some(&1));
+        for b in 0..4 {
+            buf.fetch(page_id(1, b)).unwrap();
+        }
+        assert_eq!(buf.stats().hits, stats.hits + 4);
+    }
+
+    #[test]
+    fn test_shards() {
+        let db = test_db("buffer_shards");
+        create_file(&db, "1").unwrap();
+        for _ in 0..64 {
+            append_block(&db, "1").unwrap();
+        }
+        let buf = Arc::new(PageBuffer::with_shards(Arc::clone(&db), 16, 4, Policy::Clock));
+        assert_eq!(buf.shard_count(), 4);
+        assert_eq!(PageBuffer::with_shards(Arc::clone(&db), 2, 4, Policy::Clock).shard_count(), 2);
+        let page_id = |b: u128| (1u128 << 64) | b;
+        // a scan of consecutive blocks uses every shard
+        for b in 0..16 {
+            buf.fetch(page_id(b)).unwrap();
+        }
+        assert!(buf.shards.iter().all(|s| !s.table.read().unwrap().is_empty()));
+
+        let threads: Vec<_> = (0..4).map(|t| {
+            let buf = Arc::clone(&buf);
+            thread::spawn(move || for i in 0..500 {
+                let page = buf.fetch(page_id((i * (t + 3)) % 64)).unwrap();
+                assert_eq!(page.read().unwrap().page_id, Some(page_id((i * (t + 3)) % 64)));
+            })
+        }).collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        check_tables(&buf);
+
+        let ring = buf.scan_strategy(64);
+        for b in 0..64 {
+            buf.fetch_with(page_id(b), &ring).unwrap();
+        }
+        check_tables(&buf);
+        buf.flush().unwrap();
+        assert!(buf.stats().resident.is_empty());
+    }
+
+    #[test]
+    fn test_prefetch() {
+        let db = test_db("buffer_prefetch");
+        create_file(&db, "1").unwrap();
+        for _ in 0..20 {
+            append_block(&db, "1").unwrap();
+        }
+        let buf = PageBuffer::with_shards(Arc::clone(&db), 16, 2, Policy::Clock);
+        let page_id = |b: u128| (1u128 << 64) | b;
+        buf.fetch(page_id(2)).unwrap();
+        assert_eq!(buf.read_ahead_window(&Strategy::Normal), 2);
+        assert_eq!(buf.prefetch(page_id(0), 8, &Strategy::Normal).unwrap(), 7);
+        check_tables(&buf);
+        for b in 0..8 {
+            buf.fetch(page_id(b)).unwrap();
+        }
+        let stats = buf.stats();
+        assert_eq!((stats.misses, stats.hits, stats.prefetched), (1, 8, 7));
+        assert_eq!(buf.prefetch(page_id(0), 8, &Strategy::Normal).unwrap(), 0);
+        // stops at the end of the file
+        assert_eq!(buf.prefetch(page_id(16), 8, &Strategy::Normal).unwrap(), 4);
+        assert!(buf.prefetch(page_id(20), 8, &Strategy::Normal).is_err());
+        check_tables(&buf);
+    }
+
+    #[test]
+    fn test_resize() {
+        let db = test_db("buffer_resize");
+        create_file(&db, "1").unwrap();
+        for _ in 0..16 {
+            append_block(&db, "1").unwrap();
+        }
+        let buf = PageBuffer::with_capacity(Arc::clone(&db), 4, 16, 2, Policy::Clock);
+        let page_id = |b: u128| (1u128 << 64) | b;
+        assert_eq!((buf.size(), buf.capacity()), (4, 16));
+        assert_eq!(buf.resize(64).unwrap(), 16);
+        for b in 0..16 {
+            buf.fetch(page_id(b)).unwrap().write().unwrap().toggle_dirty();
+        }
+        let before = buf.stats();
+        assert!(before.resident[&1] > 4);
+
+        let pinned = buf.fetch(page_id(5)).unwrap();
+        let size = buf.resize(2).unwrap();
+        assert!((2..16).contains(&size));
+        check_tables(&buf);
+        assert_eq!(pinned.read().unwrap().page_id, Some(page_id(5)));
+        // every page given up was dirty and written back first
+        let after = buf.stats();
+        assert_eq!((after.writes - before.writes) as usize, before.resident[&1] - after.resident[&1]);
+
+        drop(pinned);
+        assert_eq!(buf.resize(0).unwrap(), 2);
+        check_tables(&buf);
+        for b in 0..16 {
+            assert_eq!(buf.fetch(page_id(b)).unwrap().read().unwrap().page_id, Some(page_id(b)));
+        }
+        assert_eq!(buf.resize(8).unwrap(), 8);
+        check_tables(&buf);
+    }
+}
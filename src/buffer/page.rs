@@ -1,8 +1,22 @@
-use crate::{storage::{Block, LOCSIZ, Flags}, error::{Error, PageError}};
+use crate::{storage::{Block, LOCSIZ, DATSIZ, Flags}, error::{Error, PageError}};
 
 use super::tuple::{Tuple, Schema, DatumSerde};
 
-#[derive(Clone, Debug)]
+/// First byte of every encoded row: the columns follow inline, or some of its strings were
+/// moved to overflow blocks and the row keeps only their length and first block. A piece of
+/// such a string is stored as a tuple of its own that scans skip.
+pub const ROW_INLINE: u8 = 0;
+pub const ROW_OVERFLOW: u8 = 1;
+pub const ROW_CHUNK: u8 = 2;
+
+/// Space handed back by compacting a page.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Reclaimed {
+    pub bytes: u64,
+    pub slots: u64
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Page {
     pub page_id: Option<u128>,
     pub block: Option<Block>
@@ -14,69 +28,182 @@ impl Page {
     }
 }
 
+/// A slot holds the tuple's offset followed by its length; an offset of 0xFFFF marks a deleted tuple.
+fn slot(block: &Block, tup_idx: u16) -> Result<(u16, u16), Error> {
+    let start = (tup_idx * LOCSIZ) as usize;
+    if start >= block.lower as usize {return Err(Error::PageError(PageError::OutOfBounds));}
+    let loc = block.data[start] as u16 | (block.data[start+1] as u16)<<8;
+    let len = block.data[start+2] as u16 | (block.data[start+3] as u16)<<8;
+    Ok((loc, len))
+}
+
+fn set_slot(block: &mut Block, tup_idx: u16, loc: u16, len: u16) {
+    let start = (tup_idx * LOCSIZ) as usize;
+    block.data[start..start+2].copy_from_slice(&loc.to_le_bytes());
+    block.data[start+2..start+4].copy_from_slice(&len.to_le_bytes());
+}
+
 pub trait TupleCRUD {
-    fn write(&mut self, data: &[u8]) -> Result<(), Error>;
-    fn read(&self, tup_idx: u16, tup_siz: u16) -> Result<Option<Vec<u8>>, Error>;
+    fn write(&mut self, data: &[u8]) -> Result<u16, Error>;
+    fn write_slot(&mut self, tup_idx: u16, data: &[u8]) -> Result<(), Error>;
+    fn read(&self, tup_idx: u16) -> Result<Option<Vec<u8>>, Error>;
     fn update(&mut self, tup_idx: u16, data: &[u8]) -> Result<(), Error>;
     fn delete(&mut self, tup_idx: u16) -> Result<(), Error>;
 } 
 
 impl TupleCRUD for Page {
-    fn write(&mut self, data: &[u8]) -> Result<(), Error> {
+    fn write(&mut self, data: &[u8]) -> Result<u16, Error> {
+        let Some(block) = &self.block else {return Err(Error::PageError(PageError::NoBlock))};
+        let dead = (0..block.lower / LOCSIZ).find(|s| slot(block, *s).map(|(loc, _)| loc == 0xFFFF).unwrap_or(false));
+        let tup_idx = dead.unwrap_or(block.lower / LOCSIZ);
+        self.write_slot(tup_idx, data)?;
+        Ok(tup_idx)
+    }
+
+    /// Writes the tuple into a deleted slot or the next new one, so that replaying an insert
+    /// puts it back in the slot it was logged with.
+    fn write_slot(&mut self, tup_idx: u16, data: &[u8]) -> Result<(), Error> {
         let Some(block) = &mut self.block else {return Err(Error::PageError(PageError::NoBlock))};
         let write_len = data.len() as u16;
+        let slot_len = if tup_idx == block.lower / LOCSIZ {LOCSIZ} else if slot(block, tup_idx)?.0 == 0xFFFF {0} else {return Err(Error::PageError(PageError::InvalidTuple))};
 
-        if block.lower + LOCSIZ >= block.upper - write_len {return Err(Error::PageError(PageError::OutOfBounds));}
+        if block.lower + slot_len + write_len >= block.upper {return Err(Error::PageError(PageError::OutOfBounds));}
 
         block.upper -= write_len;
-        block.lower += LOCSIZ;
-        block.data[(block.lower-LOCSIZ) as usize..block.lower as usize].copy_from_slice(&block.upper.to_le_bytes());
-        block.data[block.upper as usize..(block.upper+write_len) as usize].copy_from_slice(&data);
+        block.lower += slot_len;
+        set_slot(block, tup_idx, block.upper, write_len);
+        block.data[block.upper as usize..(block.upper+write_len) as usize].copy_from_slice(data);
         block.set_flag(&Flags::Dirty);
         Ok(())
     }
 
-    fn read(&self, tup_idx: u16, tup_siz: u16) -> Result<Option<Vec<u8>>, Error> {
+    fn read(&self, tup_idx: u16) -> Result<Option<Vec<u8>>, Error> {
         let Some(block) = &self.block else {return Err(Error::PageError(PageError::NoBlock))};
-        let start = tup_idx * LOCSIZ;
-        if start >= block.lower {return Err(Error::PageError(PageError::OutOfBounds))}
-
-        let tup_loc = block.data[start as usize] as u16 | (block.data[start as usize+1] as u16)<<8;
+        let (tup_loc, tup_len) = slot(block, tup_idx)?;
         if tup_loc == 0xFFFF { return Ok(None);}
-        Ok(Some(block.data[tup_loc as usize..(tup_loc+tup_siz) as usize].to_vec()))
+        Ok(Some(block.data[tup_loc as usize..(tup_loc+tup_len) as usize].to_vec()))
     }
 
+    /// Overwrites the tuple in place when the new value fits in the old one's space,
+    /// otherwise moves it into the free gap; the old bytes are reclaimed by `compact`.
     fn update(&mut self, tup_idx: u16, data: &[u8]) -> Result<(), Error> {
         let Some(block) = &mut self.block else {return Err(Error::PageError(PageError::NoBlock))};
-        let start = tup_idx * LOCSIZ;
-        if start >= block.lower {return Err(Error::PageError(PageError::OutOfBounds));}
+        let (mut tup_loc, tup_len) = slot(block, tup_idx)?;
+        if tup_loc == 0xFFFF {return Err(Error::PageError(PageError::InvalidTuple));}
+        let write_len = data.len() as u16;
 
-        let tup_loc = block.data[start as usize] as u16 | (block.data[start as usize+1] as u16)<<8;
-        block.data[tup_loc as usize..(tup_loc+4) as usize].copy_from_slice(&data);
+        if write_len > tup_len {
+            if block.lower + write_len >= block.upper {return Err(Error::PageError(PageError::OutOfBounds));}
+            block.upper -= write_len;
+            tup_loc = block.upper;
+        }
+        set_slot(block, tup_idx, tup_loc, write_len);
+        block.data[tup_loc as usize..(tup_loc+write_len) as usize].copy_from_slice(data);
+        block.set_flag(&Flags::Dirty);
         Ok(())
     }
 
     fn delete(&mut self, tup_idx: u16) -> Result<(), Error> {
         let Some(block) = &mut self.block else {return Err(Error::PageError(PageError::NoBlock))};
-        let start = tup_idx * LOCSIZ;
-        if start >= block.lower {return Err(Error::PageError(PageError::OutOfBounds));}
-        
-        block.data[start as usize] = 0xFF;
-        block.data[start as usize + 1] =0xFF;
+        let (_, tup_len) = slot(block, tup_idx)?;
+        set_slot(block, tup_idx, 0xFFFF, tup_len);
+        block.set_flag(&Flags::Dirty);
         Ok(())
     }
 }
 
 impl Page {
 
-    pub fn add(&mut self, tuple: Tuple, schema: &Schema) -> Result<(), Error> {
+    pub fn encode(tuple: &Tuple, schema: &Schema) -> Result<Vec<u8>, Error> {
+        Ok(Self::row(ROW_INLINE, Self::encode_columns(tuple, schema)?))
+    }
+
+    /// Encodes every column on its own, in schema order.
+    pub fn encode_columns(tuple: &Tuple, schema: &Schema) -> Result<Vec<Vec<u8>>, Error> {
         if schema.len() != tuple.len() {return Err(Error::PageError(PageError::InvalidTuple));}
-        let bytes: Vec<u8> = schema
-                                    .iter()
-                                    .zip(tuple.iter())
-                                    .flat_map(|((_, ty), val)| ty.encode(val).expect("type mismatch"))
-                                    .collect();
-        self.write(&bytes)
+        schema.iter().zip(tuple.iter()).map(|((_, ty), val)| ty.encode(val).ok_or(Error::TypeMismatch)).collect()
+    }
+
+    /// Puts the row marker in front of the encoded columns.
+    pub fn row(marker: u8, columns: Vec<Vec<u8>>) -> Vec<u8> {
+        let mut bytes = vec![marker];
+        for col in columns {
+            bytes.extend(col);
+        }
+        bytes
+    }
+
+    pub fn add(&mut self, tuple: Tuple, schema: &Schema) -> Result<(), Error> {
+        let bytes = Self::encode(&tuple, schema)?;
+        self.write(&bytes).map(|_| ())
+    }
+
+    pub fn location(&self, tup_idx: u16) -> Result<u16, Error> {
+        let Some(block) = &self.block else {return Err(Error::PageError(PageError::NoBlock))};
+        slot(block, tup_idx).map(|(loc, _)| loc)
+    }
+
+    pub fn set_location(&mut self, tup_idx: u16, loc: u16) -> Result<(), Error> {
+        let Some(block) = &mut self.block else {return Err(Error::PageError(PageError::NoBlock))};
+        let (_, tup_len) = slot(block, tup_idx)?;
+        set_slot(block, tup_idx, loc, tup_len);
+        block.set_flag(&Flags::Dirty);
+        Ok(())
+    }
+
+    /// Whether every tuple of the block was deleted.
+    pub fn is_empty(&self) -> bool {
+        let Some(block) = &self.block else { return true; };
+        (0..block.lower / LOCSIZ).all(|s| slot(block, s).map(|(loc, _)| loc == 0xFFFF).unwrap_or(true))
+    }
+
+    /// Bytes between the slot array and the tuple data.
+    pub fn free_space(&self) -> u16 {
+        self.block.as_ref().map(|b| b.upper - b.lower).unwrap_or(0)
+    }
+
+    /// Packs the live tuples against the end of the block so the space of deleted ones
+    /// joins the free gap, and drops deleted slots from the end of the slot array.
+    /// Indices of the remaining slots are left untouched.
+    pub fn compact(&mut self) -> Result<Reclaimed, Error> {
+        let Some(block) = &mut self.block else {return Err(Error::PageError(PageError::NoBlock))};
+        let free = block.upper - block.lower;
+        let mut slots = 0;
+        while block.lower > 0 && slot(block, block.lower / LOCSIZ - 1)?.0 == 0xFFFF {
+            block.lower -= LOCSIZ;
+            slots += 1;
+        }
+        let mut packed = Block { data: [0; DATSIZ], ..block.clone() };
+        let mut upper = DATSIZ as u16;
+        for tup_idx in 0..block.lower / LOCSIZ {
+            let (tup_loc, tup_len) = slot(block, tup_idx)?;
+            if tup_loc != 0xFFFF {
+                upper -= tup_len;
+                packed.data[upper as usize..(upper+tup_len) as usize].copy_from_slice(&block.data[tup_loc as usize..(tup_loc+tup_len) as usize]);
+                set_slot(&mut packed, tup_idx, upper, tup_len);
+            } else {
+                set_slot(&mut packed, tup_idx, tup_loc, tup_len);
+            }
+        }
+        block.data = packed.data;
+        block.upper = upper;
+        block.set_flag(&Flags::Dirty);
+        Ok(Reclaimed { bytes: (block.upper - block.lower - free) as u64, slots })
+    }
+
+    /// Whether a row of `len` bytes fits in an empty block.
+    pub fn fits(len: usize) -> bool {
+        len + (LOCSIZ as usize) < DATSIZ
+    }
+
+    pub fn lsn(&self) -> u64 {
+        self.block.as_ref().map(|b| b.lsn).unwrap_or(0)
+    }
+
+    pub fn set_lsn(&mut self, lsn: u64) -> Option<()> {
+        let Some(b) = &mut self.block else { return None };
+        b.lsn = lsn;
+        Some(())
     }
 
     pub fn toggle_dirty(&mut self) -> Option<()> {
@@ -92,8 +219,7 @@ impl Page {
 
     pub fn has_next(&self) -> bool {
         let Some(b) = &self.block else { return false; };
-        b.check_flag(&Flags::Next);
-        return false;
+        b.check_flag(&Flags::Next)
     }
 
     pub fn get_next(&self) -> Option<u32> {
@@ -109,22 +235,13 @@ impl Page {
     }
 }
 
-impl Default for Page {
-    fn default() -> Self {
-        Self {
-            page_id: None,
-            block: None
-        }
-    }
-}
-
 
 #[cfg(test)]
 mod tests {
 
     use crate::{buffer::TupleCRUD, storage::{DATSIZ, Block}};
 
-    use super::Page;
+    use super::{Page, Reclaimed};
 
     #[test]
     fn test_tuple_write() {
@@ -137,9 +254,10 @@ mod tests {
         p.write(&bincode::serialize(&tuple).unwrap()).unwrap();
 
         let Some(b) = &p.block else {panic!()};
-        assert_eq!(b.data[..2], [231, 31]);
+        assert_eq!(b.data[..2], (DATSIZ as u16-4).to_le_bytes());
+        assert_eq!(b.data[2..4], 4u16.to_le_bytes());
         assert_eq!(tuple, bincode::deserialize(&b.data[b.data.len()-4..]).unwrap());
-        assert_eq!(b.lower, 2);
+        assert_eq!(b.lower, 4);
         assert_eq!(b.upper, DATSIZ as u16-4);
     }
 
@@ -153,11 +271,37 @@ mod tests {
         let tuple: (u16, u16) = (12, 14);
         p.write(&bincode::serialize(&tuple).unwrap()).unwrap();
 
-        let t = p.read(0, bincode::serialized_size(&tuple).unwrap() as u16).unwrap().unwrap();
+        let t = p.read(0).unwrap().unwrap();
 
         assert_eq!(tuple, bincode::deserialize(&t).unwrap());
     }
 
+    #[test]
+    fn test_variable_length() {
+        let mut p = Page {
+            page_id: Some(0),
+            block: Some(Block::new(0))
+        };
+        p.write(&[1]).unwrap();
+        p.write(&[2, 2, 2]).unwrap();
+        p.write(&[3, 3]).unwrap();
+
+        assert_eq!(p.read(0).unwrap().unwrap(), vec![1]);
+        assert_eq!(p.read(1).unwrap().unwrap(), vec![2, 2, 2]);
+        assert_eq!(p.read(2).unwrap().unwrap(), vec![3, 3]);
+
+        p.update(1, &[4]).unwrap();
+        assert_eq!(p.read(1).unwrap().unwrap(), vec![4]);
+        p.update(0, &[5, 5, 5, 5]).unwrap();
+        assert_eq!(p.read(0).unwrap().unwrap(), vec![5, 5, 5, 5]);
+        assert_eq!(p.free_space(), DATSIZ as u16 - 3*4 - 10);
+
+        assert_eq!(p.compact().unwrap(), Reclaimed { bytes: 3, slots: 0 });
+        assert_eq!(p.read(0).unwrap().unwrap(), vec![5, 5, 5, 5]);
+        assert_eq!(p.read(1).unwrap().unwrap(), vec![4]);
+        assert_eq!(p.read(2).unwrap().unwrap(), vec![3, 3]);
+    }
+
     #[test]
     fn test_tuple_update() {
         let mut p = Page {
@@ -174,6 +318,49 @@ mod tests {
         assert_eq!(tuple, bincode::deserialize(&b.data[b.data.len()-4..]).unwrap());
     }
 
+    #[test]
+    fn test_compact() {
+        let mut p = Page {
+            page_id: Some(0),
+            block: Some(Block::new(0))
+        };
+        for i in 0..4u32 {
+            p.write(&i.to_le_bytes()).unwrap();
+        }
+        p.delete(1).unwrap();
+        p.delete(3).unwrap();
+
+        assert_eq!(p.compact().unwrap(), Reclaimed { bytes: 4 + 2*4, slots: 1 });
+        assert_eq!(p.free_space(), DATSIZ as u16 - 3*4 - 2*4);
+        assert_eq!(p.read(0).unwrap().unwrap(), 0u32.to_le_bytes());
+        assert_eq!(p.read(1).unwrap(), None);
+        assert_eq!(p.read(2).unwrap().unwrap(), 2u32.to_le_bytes());
+        assert!(p.read(3).is_err());
+
+        assert_eq!(p.write(&4u32.to_le_bytes()).unwrap(), 1);
+        assert_eq!(p.free_space(), DATSIZ as u16 - 3*4 - 3*4);
+    }
+
+    #[test]
+    fn test_write_slot() {
+        let mut p = Page {
+            page_id: Some(0),
+            block: Some(Block::new(0))
+        };
+        for i in 0..3u32 {
+            p.write(&i.to_le_bytes()).unwrap();
+        }
+        p.delete(0).unwrap();
+
+        assert!(p.write_slot(1, &[9]).is_err());
+        assert!(p.write_slot(4, &[9]).is_err());
+        p.write_slot(3, &[3]).unwrap();
+        assert_eq!(p.read(0).unwrap(), None);
+        assert_eq!(p.read(3).unwrap().unwrap(), vec![3]);
+        p.write_slot(0, &[0]).unwrap();
+        assert_eq!(p.read(0).unwrap().unwrap(), vec![0]);
+    }
+
     #[test]
     fn test_tuple_delete() {
         let mut p = Page {
@@ -0,0 +1,71 @@
+use std::ops::Range;
+
+/// Blocks a sequential scan reads ahead at most in one go.
+pub const READ_AHEAD: usize = 16;
+
+/// Consecutive block reads after which a scan counts as sequential.
+const TRIGGER: u64 = 2;
+
+/// Watches the blocks a scan moves to and tells when to read the next ones ahead of it.
+///
+/// A scan is taken to be sequential once it has moved to the following block `TRIGGER` times in
+/// a row, or right away when hinted. From then on, whenever it reaches a block that was not read
+/// ahead yet, the next `window` blocks from there are.
+#[derive(Debug, Clone)]
+pub struct ReadAhead {
+    window: usize,
+    last: Option<u64>,
+    run: u64,
+    until: u64
+}
+
+impl ReadAhead {
+    pub fn new(window: usize) -> Self {
+        Self { window, last: None, run: 0, until: 0 }
+    }
+
+    /// For scans known to read every block in order, such as full table scans.
+    pub fn sequential(window: usize) -> Self {
+        Self { run: TRIGGER, ..Self::new(window) }
+    }
+
+    /// Records a move to `block` and returns the blocks to read ahead, if any, starting with it.
+    pub fn advance(&mut self, block: u64) -> Option<Range<u64>> {
+        match self.last {
+            Some(last) if last + 1 == block => self.run += 1,
+            Some(_) => { self.run = 0; self.until = 0; },
+            None => ()
+        }
+        self.last = Some(block);
+        if self.window < 2 || self.run < TRIGGER || (block < self.until && self.until - block > self.window as u64 / 2) { return None; }
+        let start = block.max(self.until);
+        self.until = block + self.window as u64;
+        Some(start..self.until)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ReadAhead;
+
+    #[test]
+    fn test_read_ahead() {
+        let mut r = ReadAhead::new(8);
+        assert_eq!(r.advance(0), None);
+        assert_eq!(r.advance(1), None);
+        assert_eq!(r.advance(2), Some(2..10));
+        assert_eq!((3..6).filter_map(|b| r.advance(b)).count(), 0);
+        // half of the window is left, so the next blocks are read while the scan is still ahead
+        assert_eq!(r.advance(6), Some(10..14));
+        // a jump starts over
+        assert_eq!(r.advance(40), None);
+        assert_eq!(r.advance(41), None);
+        assert_eq!(r.advance(42), Some(42..50));
+
+        let mut r = ReadAhead::sequential(8);
+        assert_eq!(r.advance(0), Some(0..8));
+        assert_eq!((1..4).filter_map(|b| r.advance(b)).count(), 0);
+        assert_eq!(r.advance(4), Some(8..12));
+        assert_eq!(ReadAhead::sequential(1).advance(0), None);
+    }
+}
@@ -0,0 +1,71 @@
+use std::{sync::atomic::{AtomicU64, Ordering}, collections::HashMap, ops::AddAssign};
+
+/// Running counters of a buffer pool shard, bumped without taking any of its locks.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub fetches: AtomicU64,
+    pub hits: AtomicU64,
+    pub misses: AtomicU64,
+    pub evictions: AtomicU64,
+    pub writes: AtomicU64,
+    pub failed_writes: AtomicU64,
+    pub prefetched: AtomicU64
+}
+
+impl Counters {
+    pub fn bump(counter: &AtomicU64) {
+        counter.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// A snapshot of the counters plus the number of resident pages of every data file.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct BufferStats {
+    pub fetches: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+    /// Dirty pages written back, whether on eviction, flush or by the background writer.
+    pub writes: u64,
+    /// Write-backs that failed and left their page dirty.
+    pub failed_writes: u64,
+    /// Pages read ahead of a sequential scan.
+    pub prefetched: u64,
+    pub resident: HashMap<u64, usize>
+}
+
+impl BufferStats {
+    pub fn hit_ratio(&self) -> f64 {
+        if self.fetches == 0 { return 0.0; }
+        self.hits as f64 / self.fetches as f64
+    }
+}
+
+impl From<&Counters> for BufferStats {
+    fn from(c: &Counters) -> Self {
+        Self {
+            fetches: c.fetches.load(Ordering::Relaxed),
+            hits: c.hits.load(Ordering::Relaxed),
+            misses: c.misses.load(Ordering::Relaxed),
+            evictions: c.evictions.load(Ordering::Relaxed),
+            writes: c.writes.load(Ordering::Relaxed),
+            failed_writes: c.failed_writes.load(Ordering::Relaxed),
+            prefetched: c.prefetched.load(Ordering::Relaxed),
+            resident: HashMap::new()
+        }
+    }
+}
+
+/// Adds the counters of another shard of the same pool.
+impl AddAssign<&Counters> for BufferStats {
+    fn add_assign(&mut self, c: &Counters) {
+        let other = Self::from(c);
+        self.fetches += other.fetches;
+        self.hits += other.hits;
+        self.misses += other.misses;
+        self.evictions += other.evictions;
+        self.writes += other.writes;
+        self.failed_writes += other.failed_writes;
+        self.prefetched += other.prefetched;
+    }
+}
@@ -0,0 +1,45 @@
+use std::{sync::Mutex, collections::VecDeque, ops::Range};
+
+/// Frames a bulk scan may cycle through at most.
+pub const RING_FRAMES: usize = 32;
+
+/// How a fetch that misses claims a frame.
+pub enum Strategy {
+    /// Any frame the keeper picks.
+    Normal,
+    /// The frames the ring itself filled before, once it has `cap` of them in the shard of the
+    /// page being read, so a bulk scan or hash table build only ever displaces that many pages
+    /// of every shard.
+    Ring(Ring)
+}
+
+impl Strategy {
+    pub fn ring(cap: usize) -> Self {
+        Self::Ring(Ring { cap: cap.max(1), frames: Mutex::new(VecDeque::new()) })
+    }
+}
+
+pub struct Ring {
+    cap: usize,
+    frames: Mutex<VecDeque<(usize, u128)>>
+}
+
+impl Ring {
+    /// The oldest frame among `shard` of a ring full there, with the page the ring put there.
+    /// The frame is given up by the ring either way; if it has been reused or pinned since, the
+    /// ring takes a new one.
+    pub(super) fn reusable(&self, shard: Range<usize>) -> Option<(usize, u128)> {
+        let mut frames = self.frames.lock().unwrap();
+        if frames.iter().filter(|(idx, _)| shard.contains(idx)).count() < self.cap { return None; }
+        let pos = frames.iter().position(|(idx, _)| shard.contains(idx))?;
+        frames.remove(pos)
+    }
+
+    pub(super) fn cap(&self) -> usize {
+        self.cap
+    }
+
+    pub(super) fn push(&self, idx: usize, page_id: u128) {
+        self.frames.lock().unwrap().push_back((idx, page_id));
+    }
+}
@@ -1,39 +1,92 @@
-use std::{io::Write, sync::{RwLock, Arc}, fmt::Debug, ptr};
+use std::{sync::{RwLock, Arc}, fmt::Debug, any::Any, cmp::Reverse};
 
 use serde::{Serialize, Deserialize};
 
-use crate::{storage::{utils::{create_file, append_block, delete_file}, folder::{Folder, TableInode}, disk_manager::SET_64}, error::{Error, PageError}};
+use crate::{storage::{utils::{overwrite_file, append_block, delete_file}, folder::{Folder, TableInode}, disk_manager::SET_64, database::Database, wal::LogBody, DATSIZ, LOCSIZ}, error::{Error, PageError}};
 
-use super::{Buff, page::{TupleCRUD, Page}, Buffer, BufferInner, Clock};
+use super::{Buff, page::{TupleCRUD, Page, Reclaimed, ROW_INLINE, ROW_OVERFLOW, ROW_CHUNK}, Buffer, BufferInner, Keeper, strategy::Strategy, read_ahead::ReadAhead};
 
 pub type Tuple = Vec<Datum>;
 pub type Schema = Vec<(String, DatumTypes)>;
-pub type PageBuffer = Buffer<RwLock<Page>, BufferInner<RwLock<Page>>, Clock>;
+pub type PageBuffer = Buffer<RwLock<Page>, BufferInner<RwLock<Page>>, Box<dyn Keeper + Send>>;
 
 #[derive(Debug, PartialEq, Clone, Serialize, Deserialize)]
 pub enum Datum {
     Int(i32),
-    Float(f32)
+    Float(f32),
+    Text(String),
+    BigInt(i64),
+    SmallInt(i16),
+    Double(f64),
+    Bool(bool)
 }
 
+impl Datum {
+    fn integer(&self) -> Option<i64> {
+        match *self {
+            Self::Int(i) => Some(i as i64),
+            Self::BigInt(i) => Some(i),
+            Self::SmallInt(i) => Some(i as i64),
+            _ => None
+        }
+    }
+
+    /// Equality between values of types `DatumTypes::compatible` lets a join compare: integers
+    /// compare by value whatever their width.
+    pub fn join_eq(&self, other: &Self) -> bool {
+        if let (Some(a), Some(b)) = (self.integer(), other.integer()) { return a == b; }
+        self == other
+    }
+}
+
+/// Integers of every width hash alike, so that `join_eq` values land in the same bucket.
 impl Hash for Datum {
     fn hash(&self) -> u16 {
         match self {
-            Self::Int(i) => i.hash(),
-            Self::Float(f) => f.hash()
+            Self::Int(_) | Self::BigInt(_) | Self::SmallInt(_) => self.integer().unwrap().hash(),
+            Self::Float(f) => f.hash(),
+            Self::Double(f) => f.hash(),
+            Self::Text(s) => s.as_str().hash(),
+            Self::Bool(b) => *b as u16
         }
     }
 }
 
 impl Hash for i32 {
     fn hash(&self) -> u16 {
-        *self as u16
+        (*self as i64).hash()
+    }
+}
+
+/// The two's complement bits folded to 16 bits, so the high words count as well.
+impl Hash for i64 {
+    fn hash(&self) -> u16 {
+        let h = *self as u64;
+        (h ^ (h >> 16) ^ (h >> 32) ^ (h >> 48)) as u16
     }
 }
 
+/// The bit pattern folded to 16 bits, with -0.0 hashing like the 0.0 it equals.
 impl Hash for f32 {
     fn hash(&self) -> u16 {
-        *self as u16
+        let h = if *self == 0.0 { 0 } else { self.to_bits() };
+        (h ^ (h >> 16)) as u16
+    }
+}
+
+/// Like `f32`, over all four words.
+impl Hash for f64 {
+    fn hash(&self) -> u16 {
+        let h = if *self == 0.0 { 0 } else { self.to_bits() };
+        (h ^ (h >> 16) ^ (h >> 32) ^ (h >> 48)) as u16
+    }
+}
+
+/// FNV-1a over the bytes, folded to 16 bits.
+impl Hash for str {
+    fn hash(&self) -> u16 {
+        let h = self.bytes().fold(0xcbf29ce484222325u64, |h, b| (h ^ b as u64).wrapping_mul(0x100000001b3));
+        (h ^ (h >> 16) ^ (h >> 32) ^ (h >> 48)) as u16
     }
 }
 
@@ -45,45 +98,110 @@ pub trait Hash {
 #[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub enum DatumTypes {
     Int,
-    Float
+    Float,
+    /// Strings of at most this many characters.
+    Varchar(u32),
+    Text,
+    BigInt,
+    SmallInt,
+    Double,
+    Boolean
 }
 
+/// Bytes in front of an encoded string holding its length.
+const TEXT_PREFIX: usize = 4;
+/// Set in the length of a string whose bytes were moved to overflow blocks, the number of the
+/// first one follows the length in the row.
+const SPILLED: u32 = 1 << 31;
+/// Bytes a spilled string keeps in its row.
+const SPILL_PTR: usize = TEXT_PREFIX + 4;
+/// Bytes of a spilled string each overflow block holds, the largest tuple of an empty block
+/// once the `ROW_CHUNK` marker is in front.
+const CHUNK: usize = DATSIZ - LOCSIZ as usize - 2;
+
 impl DatumTypes {
-    fn serialized_size(&self) -> u64 {
+    /// Encoded size of the fixed-size types.
+    fn serialized_size(&self) -> Option<usize> {
         match *self {
-            DatumTypes::Int => bincode::serialized_size(&(0 as i32)).unwrap(),
-            DatumTypes::Float => bincode::serialized_size(&(0 as f32)).unwrap()
+            DatumTypes::Int => Some(bincode::serialized_size(&0i32).unwrap() as usize),
+            DatumTypes::Float => Some(bincode::serialized_size(&0f32).unwrap() as usize),
+            DatumTypes::BigInt => Some(bincode::serialized_size(&0i64).unwrap() as usize),
+            DatumTypes::SmallInt => Some(bincode::serialized_size(&0i16).unwrap() as usize),
+            DatumTypes::Double => Some(bincode::serialized_size(&0f64).unwrap() as usize),
+            DatumTypes::Boolean => Some(bincode::serialized_size(&false).unwrap() as usize),
+            DatumTypes::Varchar(_) | DatumTypes::Text => None
         }
     }
 
     pub fn parse(typ: &str) -> Result<Self, Error> {
-        match typ.to_ascii_uppercase().as_str() {
+        let typ = typ.to_ascii_uppercase();
+        if let Some(n) = typ.strip_prefix("VARCHAR(").and_then(|t| t.strip_suffix(')')) {
+            return n.parse().map(Self::Varchar).map_err(|_| Error::ParseError);
+        }
+        match typ.as_str() {
             "INT" => Ok(Self::Int),
             "FLOAT" => Ok(Self::Float),
+            "TEXT" => Ok(Self::Text),
+            "BIGINT" => Ok(Self::BigInt),
+            "SMALLINT" => Ok(Self::SmallInt),
+            "DOUBLE" => Ok(Self::Double),
+            "BOOLEAN" => Ok(Self::Boolean),
             _ => Err(Error::ParseError)
         }
     }
+
+    /// Whether columns of the two types can be compared, as in a join: integers of any width
+    /// with each other, strings with strings and booleans with booleans. Floating point numbers
+    /// only match their own width, as most values of one width have no exact twin in the other.
+    pub fn compatible(&self, other: &Self) -> bool {
+        matches!((self, other),
+            (Self::SmallInt | Self::Int | Self::BigInt, Self::SmallInt | Self::Int | Self::BigInt)
+            | (Self::Float, Self::Float) | (Self::Double, Self::Double)
+            | (Self::Varchar(_) | Self::Text, Self::Varchar(_) | Self::Text)
+            | (Self::Boolean, Self::Boolean))
+    }
 }
 
 pub trait DatumSerde {
     fn encode(&self, datum: &Datum) -> Option<Vec<u8>>;
-    fn decode(&self, bytes: &[u8]) -> Option<Datum>; 
+    /// Decodes one datum from the front of `bytes` and returns it with the number of bytes consumed.
+    fn decode(&self, bytes: &[u8]) -> Option<(Datum, usize)>; 
 }
 
 impl DatumSerde for DatumTypes {
 
+    /// Numbers take their fixed size; strings are their UTF-8 bytes after a 4-byte length.
     fn encode(&self, datum: &Datum) -> Option<Vec<u8>> {
         match (self, datum) {
             (DatumTypes::Int, Datum::Int(v)) => Some(bincode::serialize(v).unwrap()),
             (DatumTypes::Float, Datum::Float(v)) => Some(bincode::serialize(v).unwrap()),
+            (DatumTypes::BigInt, Datum::BigInt(v)) => Some(bincode::serialize(v).unwrap()),
+            (DatumTypes::SmallInt, Datum::SmallInt(v)) => Some(bincode::serialize(v).unwrap()),
+            (DatumTypes::Double, Datum::Double(v)) => Some(bincode::serialize(v).unwrap()),
+            (DatumTypes::Boolean, Datum::Bool(v)) => Some(bincode::serialize(v).unwrap()),
+            (DatumTypes::Varchar(n), Datum::Text(s)) if s.chars().count() > *n as usize => None,
+            (DatumTypes::Varchar(_) | DatumTypes::Text, Datum::Text(s)) => {
+                let len = u32::try_from(s.len()).ok().filter(|len| *len < SPILLED)?;
+                Some(len.to_le_bytes().into_iter().chain(s.bytes()).collect())
+            },
             _ => None
         }
     }
 
-    fn decode(&self, bytes: &[u8]) -> Option<Datum> {
+    fn decode(&self, bytes: &[u8]) -> Option<(Datum, usize)> {
+        let siz = self.serialized_size().unwrap_or(0);
         match *self {
-            DatumTypes::Int => Some(Datum::Int(bincode::deserialize(bytes).unwrap())),
-            DatumTypes::Float => Some(Datum::Float(bincode::deserialize(bytes).unwrap()))
+            DatumTypes::Int => Some((Datum::Int(bincode::deserialize(bytes.get(..siz)?).ok()?), siz)),
+            DatumTypes::Float => Some((Datum::Float(bincode::deserialize(bytes.get(..siz)?).ok()?), siz)),
+            DatumTypes::BigInt => Some((Datum::BigInt(bincode::deserialize(bytes.get(..siz)?).ok()?), siz)),
+            DatumTypes::SmallInt => Some((Datum::SmallInt(bincode::deserialize(bytes.get(..siz)?).ok()?), siz)),
+            DatumTypes::Double => Some((Datum::Double(bincode::deserialize(bytes.get(..siz)?).ok()?), siz)),
+            DatumTypes::Boolean => Some((Datum::Bool(bincode::deserialize(bytes.get(..siz)?).ok()?), siz)),
+            DatumTypes::Varchar(_) | DatumTypes::Text => {
+                let len = u32::from_le_bytes(bytes.get(..TEXT_PREFIX)?.try_into().ok()?) as usize;
+                let s = std::str::from_utf8(bytes.get(TEXT_PREFIX..TEXT_PREFIX + len)?).ok()?;
+                Some((Datum::Text(s.to_owned()), TEXT_PREFIX + len))
+            }
         }
     }
 }
@@ -95,6 +213,14 @@ pub trait Table {
     fn set_temp(&mut self, temp: bool);
     fn schema(&self) -> Schema;
     fn set_schema(&mut self, schema: Schema);
+    fn set_db(&mut self, db: Arc<Database>);
+    /// Forgets every block of the table once its data file has been emptied.
+    fn reset(&mut self);
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    /// Marks a copy of a header that others may be changing, which must not save over it;
+    /// writers go through `Folder::update_table` instead.
+    fn set_read_only(&mut self) {}
     fn create(f: Arc<Folder>, name: &str, schema: Schema) -> Result<Self, Error> where Self: Sized;
     fn create_temp(f: Arc<Folder>, schema: Schema) -> Result<Self, Error> where Self: Sized;
     fn new(f: Arc<Folder>, name: &str) -> Result<Self, Error> where Self: Sized;
@@ -105,13 +231,19 @@ pub struct RowTable {
     pub inode: TableInode,
     pub temp: bool,
     pub num_blocks: u64,
-    pub schema: Schema
+    pub schema: Schema,
+    /// Free space map, the bytes each block can hand out once compacted.
+    pub free: Vec<u16>,
+    #[serde(skip)]
+    pub db: Option<Arc<Database>>,
+    #[serde(skip)]
+    read_only: bool
 }
 
 impl Drop for RowTable {
     fn drop(&mut self) {
         if self.temp() { 
-            delete_file(&self.inode.data_ino.to_string()).unwrap();
+            delete_file(self.db(), &self.inode.data_ino.to_string()).unwrap();
         }
     }
 }
@@ -141,8 +273,29 @@ impl Table for RowTable {
         self.schema = schema
     }
 
+    fn set_db(&mut self, db: Arc<Database>) {
+        self.db = Some(db)
+    }
+
+    fn reset(&mut self) {
+        self.num_blocks = 0;
+        self.free.clear();
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn set_read_only(&mut self) {
+        self.read_only = true
+    }
+
     fn create(f: Arc<Folder>, name: &str, schema: Schema) -> Result<Self, Error> {
-        Ok(f.create_table(name, schema)?)
+        f.create_table(name, schema)
     }
 
     fn create_temp(f: Arc<Folder>, schema: Schema) -> Result<Self, Error> {
@@ -150,7 +303,7 @@ impl Table for RowTable {
     }
 
     fn new(f: Arc<Folder>, name: &str) -> Result<Self, Error> {
-        Ok((f.fetch_table(&name)?).ok_or(Error::TableDoesNotExist)?)
+        f.fetch_table(name)?.ok_or(Error::TableDoesNotExist)
     }
 }
 
@@ -160,42 +313,325 @@ impl Default for RowTable {
             inode: TableInode::new(0, 0),
             temp: false,
             num_blocks: 0,
-            schema: vec![]
+            schema: vec![],
+            free: vec![],
+            db: None,
+            read_only: false
         }
     }
 }
 
 impl RowTable {
-    fn append_block(&mut self) -> Option<()> {
-        append_block(&self.inode.data_ino.to_string()).unwrap();
+    fn db(&self) -> &Database {
+        self.db.as_ref().expect("table is not attached to a database")
+    }
+
+    fn page_id(&self, block_num: u64) -> u128 {
+        ((self.inode.data_ino as u128)<<64) | (block_num & SET_64) as u128
+    }
+
+    /// Fails on read-only copies, before a write touches any page.
+    fn writable(&self) -> Result<(), Error> {
+        if self.read_only { Err(Error::ReadOnly) } else { Ok(()) }
+    }
+
+    fn save_header(&self) -> Result<(), Error> {
+        self.writable()?;
+        Ok(overwrite_file(self.db(), &self.inode.head_ino.to_string(), &bincode::serialize(&self).unwrap())?)
+    }
+
+    fn append_block(&mut self, txn: u64) -> Result<(), Error> {
+        let lsn = self.db().wal().append(txn, LogBody::AppendBlock { head_ino: self.inode.head_ino, data_ino: self.inode.data_ino, block_num: self.num_blocks });
+        self.db().wal().flush(lsn)?;
+        append_block(self.db(), &self.inode.data_ino.to_string())?;
         self.num_blocks += 1;
-        let mut h_file = create_file(&(self.inode.head_ino.to_string())).expect("could not create header file");
-        h_file.write_all(&bincode::serialize(&self).unwrap()).unwrap();
-        Some(())
+        self.free.resize(self.num_blocks as usize, DATSIZ as u16);
+        self.save_header()
+    }
+
+    fn insert(&mut self, p_buf: &PageBuffer, txn: u64, bytes: &[u8]) -> Result<(), Error> {
+        let needed = bytes.len() as u16 + LOCSIZ;
+        let Some(block_num) = self.free.iter().position(|free| *free > needed) else {
+            self.append_block(txn)?;
+            return self.insert(p_buf, txn, bytes);
+        };
+        let page_id = self.page_id(block_num as u64);
+        let page = p_buf.fetch(page_id)?;
+        let mut p = page.write().unwrap();
+
+        // the map is only a hint, compacting gives the exact free space of the block
+        if p.free_space() <= needed {
+            p.compact()?;
+            p.set_lsn(self.db().wal().append(txn, LogBody::Compact { page_id }));
+            self.free[block_num] = p.free_space();
+            self.save_header()?;
+        }
+        let gap = p.free_space();
+        match p.write(bytes) {
+            Ok(slot) => {
+                p.set_lsn(self.db().wal().append(txn, LogBody::Insert { page_id, slot, data: bytes.to_vec() }));
+                self.free[block_num] -= gap - p.free_space();
+                Ok(())
+            },
+            Err(Error::PageError(PageError::OutOfBounds)) => {
+                drop(p);
+                self.insert(p_buf, txn, bytes)
+            },
+            Err(e) => Err(e)
+        }
+    }
+
+    /// Picks the strings to move to overflow blocks, largest first, until the row fits a block.
+    fn spill_plan(&self, cols: &[Vec<u8>]) -> Result<Vec<usize>, Error> {
+        let mut len = 1 + cols.iter().map(Vec::len).sum::<usize>();
+        let mut strings: Vec<usize> = (0..cols.len())
+            .filter(|i| self.schema[*i].1.serialized_size().is_none() && cols[*i].len() > SPILL_PTR)
+            .collect();
+        strings.sort_by_key(|i| Reverse(cols[*i].len()));
+        let mut plan = vec![];
+        for i in strings {
+            if Page::fits(len) { break; }
+            len -= cols[i].len() - SPILL_PTR;
+            plan.push(i);
+        }
+        // the fixed-size columns alone can outgrow a block
+        if !Page::fits(len) {return Err(Error::PageError(PageError::OutOfBounds));}
+        Ok(plan)
+    }
+
+    /// Builds the row out of its encoded columns, spilling the strings picked by `spill_plan`.
+    fn encode_row(&mut self, p_buf: &PageBuffer, txn: u64, mut cols: Vec<Vec<u8>>, plan: &[usize]) -> Result<Vec<u8>, Error> {
+        for i in plan {
+            cols[*i] = self.spill(p_buf, txn, &cols[*i][TEXT_PREFIX..])?;
+        }
+        Ok(Page::row(if plan.is_empty() {ROW_INLINE} else {ROW_OVERFLOW}, cols))
+    }
+
+    /// Moves the bytes of a string into a chain of overflow blocks linked through their next
+    /// block, each piece the first tuple of its block, and returns what the row keeps.
+    fn spill(&mut self, p_buf: &PageBuffer, txn: u64, data: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut blocks = vec![];
+        for _ in 0..data.chunks(CHUNK).count() {
+            let block_num = self.empty_block(p_buf, txn, &blocks)?;
+            blocks.push(block_num);
+        }
+        for (i, chunk) in data.chunks(CHUNK).enumerate() {
+            let next = blocks.get(i + 1).map(|b| *b as u32);
+            let page_id = self.page_id(blocks[i]);
+            let page = p_buf.fetch(page_id)?;
+            let mut p = page.write().unwrap();
+            let piece = Page::row(ROW_CHUNK, vec![chunk.to_vec()]);
+            p.write_slot(0, &piece)?;
+            if let Some(next) = next { p.set_next(next); }
+            p.set_lsn(self.db().wal().append(txn, LogBody::Overflow { page_id, next, data: piece }));
+            self.free[blocks[i] as usize] = p.free_space();
+        }
+        self.save_header()?;
+
+        let mut ptr = (data.len() as u32 | SPILLED).to_le_bytes().to_vec();
+        ptr.extend((blocks[0] as u32).to_le_bytes());
+        Ok(ptr)
+    }
+
+    /// Takes a block without live tuples for a piece of a spilled string, one whose pieces were
+    /// released or whose rows were vacuumed away, and appends one when there is none. Blocks
+    /// already `taken` for the same string are passed over until their pieces are written.
+    fn empty_block(&mut self, p_buf: &PageBuffer, txn: u64, taken: &[u64]) -> Result<u64, Error> {
+        while let Some(block_num) = (0..self.free.len()).find(|b| !taken.contains(&(*b as u64)) && self.free[*b] as usize + LOCSIZ as usize >= DATSIZ) {
+            let page_id = self.page_id(block_num as u64);
+            let page = p_buf.fetch(page_id)?;
+            let mut p = page.write().unwrap();
+            if !p.is_empty() {
+                self.free[block_num] = p.free_space();
+                continue;
+            }
+            if p.free_space() as usize != DATSIZ {
+                p.compact()?;
+                p.set_lsn(self.db().wal().append(txn, LogBody::Compact { page_id }));
+            }
+            return Ok(block_num as u64);
+        }
+        self.append_block(txn)?;
+        Ok(self.num_blocks - 1)
+    }
+
+    /// Deletes the pieces of the strings a row spilled, for `empty_block` to hand their blocks out again.
+    fn release(&mut self, p_buf: &PageBuffer, txn: u64, row: &[u8]) -> Result<(), Error> {
+        if row.first() != Some(&ROW_OVERFLOW) { return Ok(()); }
+        for col in stored_columns(&self.schema, &row[1..])? {
+            let Stored::Spilled { len, mut block } = col else { continue; };
+            for _ in 0..len.div_ceil(CHUNK) {
+                let page_id = self.page_id(block as u64);
+                let page = p_buf.fetch(page_id)?;
+                let mut p = page.write().unwrap();
+                let loc = p.location(0)?;
+                let Some(piece) = p.read(0)?.filter(|piece| piece.first() == Some(&ROW_CHUNK)) else {return Err(Error::PageError(PageError::InvalidTuple))};
+                p.delete(0)?;
+                p.set_lsn(self.db().wal().append(txn, LogBody::Delete { page_id, slot: 0, loc }));
+                self.free[block as usize] += piece.len() as u16;
+                match p.get_next() {
+                    Some(next) => block = next,
+                    None => break
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Swaps the row in a slot for its new bytes and releases the strings the old one spilled.
+    fn replace(&mut self, p_buf: &PageBuffer, txn: u64, block_num: u64, tup_idx: u16, before: Vec<u8>, after: Vec<u8>) -> Result<(), Error> {
+        let page_id = self.page_id(block_num);
+        let page = p_buf.fetch(page_id)?;
+        let mut p = page.write().unwrap();
+        p.update(tup_idx, &after)?;
+        // the old bytes are dead once the tuple shrinks or moves
+        let reclaim = before.len() as u16;
+        let used = after.len() as u16;
+        p.set_lsn(self.db().wal().append(txn, LogBody::Update { page_id, slot: tup_idx, before: before.clone(), after }));
+        drop(p);
+        self.free[block_num as usize] = (self.free[block_num as usize] + reclaim).saturating_sub(used);
+        self.release(p_buf, txn, &before)?;
+        self.save_header()
+    }
+
+    fn remove(&mut self, p_buf: &PageBuffer, txn: u64, block_num: u64, tup_idx: u16) -> Result<(), Error> {
+        let page_id = self.page_id(block_num);
+        let page = p_buf.fetch(page_id)?;
+        let mut p = page.write().unwrap();
+        let loc = p.location(tup_idx)?;
+        // the pieces of a spilled string go with their row
+        let Some(bytes) = p.read(tup_idx)?.filter(|row| row.first() != Some(&ROW_CHUNK)) else {return Err(Error::PageError(PageError::InvalidTuple))};
+        p.delete(tup_idx)?;
+        p.set_lsn(self.db().wal().append(txn, LogBody::Delete { page_id, slot: tup_idx, loc }));
+        drop(p);
+        self.free[block_num as usize] += bytes.len() as u16;
+        self.release(p_buf, txn, &bytes)?;
+        self.save_header()
+    }
+
+    /// Compacts every block of the table and resets its free space map.
+    pub fn vacuum(&mut self, p_buf: Arc<PageBuffer>) -> Result<Reclaimed, Error> {
+        self.writable()?;
+        let txn = self.db().wal().begin();
+        let res = self.compact_blocks(&p_buf, txn);
+        self.finish(&p_buf, txn, res)
+    }
+
+    fn compact_blocks(&mut self, p_buf: &PageBuffer, txn: u64) -> Result<Reclaimed, Error> {
+        let mut total = Reclaimed::default();
+        for block_num in 0..self.num_blocks {
+            let page_id = self.page_id(block_num);
+            let page = p_buf.fetch(page_id)?;
+            let mut p = page.write().unwrap();
+            let reclaimed = p.compact()?;
+            p.set_lsn(self.db().wal().append(txn, LogBody::Compact { page_id }));
+            self.free[block_num as usize] = p.free_space();
+            total.bytes += reclaimed.bytes;
+            total.slots += reclaimed.slots;
+        }
+        self.save_header()?;
+        Ok(total)
+    }
+
+    /// Commits `txn` when the write it ran succeeded, and rolls it back otherwise.
+    fn finish<R>(&mut self, p_buf: &PageBuffer, txn: u64, res: Result<R, Error>) -> Result<R, Error> {
+        match res {
+            Ok(r) => {
+                self.db().wal().commit(txn)?;
+                Ok(r)
+            },
+            Err(e) => {
+                self.rollback(p_buf, txn)?;
+                Err(e)
+            }
+        }
+    }
+
+    /// Reverts what `txn` changed so far, newest first. Every reversal is logged under `txn` as
+    /// well and an abort record ends it, so the log no longer holds on to it.
+    fn rollback(&mut self, p_buf: &PageBuffer, txn: u64) -> Result<(), Error> {
+        let records = self.db().wal().records()?;
+        for r in records.iter().rev().filter(|r| r.txn == txn) {
+            match &r.body {
+                LogBody::Insert { page_id, slot, data } => self.undo_write(p_buf, txn, *page_id, *slot, data.len())?,
+                // the piece of a spilled string, always the first tuple of its block
+                LogBody::Overflow { page_id, data, .. } => self.undo_write(p_buf, txn, *page_id, 0, data.len())?,
+                LogBody::Update { page_id, slot, before, after } => {
+                    let page = p_buf.fetch(*page_id)?;
+                    let mut p = page.write().unwrap();
+                    p.update(*slot, before)?;
+                    p.set_lsn(self.db().wal().append(txn, LogBody::Update { page_id: *page_id, slot: *slot, before: after.clone(), after: before.clone() }));
+                    let free = &mut self.free[(*page_id & SET_64 as u128) as usize];
+                    *free = (*free + after.len() as u16).saturating_sub(before.len() as u16);
+                },
+                LogBody::Delete { page_id, slot, loc } => {
+                    let page = p_buf.fetch(*page_id)?;
+                    let mut p = page.write().unwrap();
+                    p.set_location(*slot, *loc)?;
+                    let Some(data) = p.read(*slot)? else {return Err(Error::PageError(PageError::InvalidTuple))};
+                    let free = &mut self.free[(*page_id & SET_64 as u128) as usize];
+                    *free = free.saturating_sub(data.len() as u16);
+                    p.set_lsn(self.db().wal().append(txn, LogBody::Insert { page_id: *page_id, slot: *slot, data }));
+                },
+                LogBody::AppendBlock { .. } | LogBody::Compact { .. } | LogBody::Truncate { .. } | LogBody::Commit | LogBody::Abort => {}
+            }
+        }
+        self.db().wal().abort(txn);
+        self.save_header()
+    }
+
+    /// Deletes a tuple `txn` wrote while rolling it back.
+    fn undo_write(&mut self, p_buf: &PageBuffer, txn: u64, page_id: u128, slot: u16, len: usize) -> Result<(), Error> {
+        let page = p_buf.fetch(page_id)?;
+        let mut p = page.write().unwrap();
+        let loc = p.location(slot)?;
+        p.delete(slot)?;
+        p.set_lsn(self.db().wal().append(txn, LogBody::Delete { page_id, slot, loc }));
+        self.free[(page_id & SET_64 as u128) as usize] += len as u16;
+        Ok(())
     }
 }
 
 pub trait TupleOps {
     fn add(&mut self, page_buffer: Arc<PageBuffer>, tuple: Tuple) -> Result<(), Error>;
+    fn update(&mut self, page_buffer: Arc<PageBuffer>, block_num: u64, tup_idx: u16, tuple: Tuple) -> Result<(), Error>;
+    fn delete(&mut self, page_buffer: Arc<PageBuffer>, block_num: u64, tup_idx: u16) -> Result<(), Error>;
 }
 
 impl TupleOps for RowTable {
     fn add(&mut self, p_buf: Arc<PageBuffer>, tuple: Tuple) -> Result<(), Error> {
-        if self.num_blocks == 0 {self.append_block();}
-        let page = p_buf.fetch(((self.inode.data_ino as u128)<<64) | ((self.num_blocks - 1) & SET_64) as u128);
-        let mut p = page.write().unwrap();
-        
-        let bind = p.add(tuple.to_vec(), &self.schema);
-        drop(p);
-        let ret = match bind {
-            Ok(()) => Ok(()),
-            Err(Error::PageError(PageError::OutOfBounds)) => {
-                self.append_block().unwrap();
-                self.add(p_buf, tuple)
-            },
-            Err(e) => Err(e)
+        self.writable()?;
+        let cols = Page::encode_columns(&tuple, &self.schema)?;
+        let plan = self.spill_plan(&cols)?;
+        let txn = self.db().wal().begin();
+        let res = self.encode_row(&p_buf, txn, cols, &plan).and_then(|bytes| self.insert(&p_buf, txn, &bytes));
+        self.finish(&p_buf, txn, res)
+    }
+
+    fn update(&mut self, p_buf: Arc<PageBuffer>, block_num: u64, tup_idx: u16, tuple: Tuple) -> Result<(), Error> {
+        self.writable()?;
+        if block_num >= self.num_blocks {return Err(Error::PageError(PageError::OutOfBounds));}
+        let cols = Page::encode_columns(&tuple, &self.schema)?;
+        let plan = self.spill_plan(&cols)?;
+        // the target is checked before any string is spilled for it
+        let before = {
+            let page = p_buf.fetch(self.page_id(block_num))?;
+            let p = page.read().unwrap();
+            p.read(tup_idx)?.filter(|row| row.first() != Some(&ROW_CHUNK))
         };
-        ret
+        let Some(before) = before else {return Err(Error::PageError(PageError::InvalidTuple))};
+
+        let txn = self.db().wal().begin();
+        let res = self.encode_row(&p_buf, txn, cols, &plan).and_then(|after| self.replace(&p_buf, txn, block_num, tup_idx, before, after));
+        self.finish(&p_buf, txn, res)
+    }
+
+    fn delete(&mut self, p_buf: Arc<PageBuffer>, block_num: u64, tup_idx: u16) -> Result<(), Error> {
+        self.writable()?;
+        if block_num >= self.num_blocks {return Err(Error::PageError(PageError::OutOfBounds));}
+        let txn = self.db().wal().begin();
+        let res = self.remove(&p_buf, txn, block_num, tup_idx);
+        self.finish(&p_buf, txn, res)
     }
 }
 
@@ -204,118 +640,199 @@ pub struct TableIter<T: Table> {
     pub buf: Arc<PageBuffer>,
     pub tup_idx: u16,
     pub table: T,
-    pub page: *const RwLock<Page>,
-    pub on_page_end: fn(&mut TableIter<T>) -> bool
+    pub strategy: Strategy,
+    pub read_ahead: ReadAhead,
+    /// Moves the iterator past the page it just finished, returning `true` when the scan is over.
+    pub on_page_end: fn(&mut TableIter<T>, &Page) -> bool,
+    pub err: Option<Error>
 }
 
 impl RowTable {
     pub fn iter(&self, buf: Arc<PageBuffer>) -> TableIter<Self> {
+        let strategy = buf.scan_strategy(self.num_blocks);
         TableIter { 
-            block_num: Some(0), 
+            block_num: (self.num_blocks > 0).then_some(0), 
+            read_ahead: ReadAhead::sequential(buf.read_ahead_window(&strategy)),
+            strategy,
             buf,
             tup_idx: 0, 
             table: self.clone(), 
-            page: ptr::null(),
-            on_page_end: |i| {
+            on_page_end: |i, _| {
                 *i.block_num.as_mut().unwrap() += 1;
                 i.tup_idx = 0;
-                if i.block_num.unwrap() >= i.table.num_blocks {true} else {false}
-            } 
+                i.block_num.unwrap() >= i.table.num_blocks
+            },
+            err: None
         }
     }
 }
 
 pub trait Operator: Iterator<Item = Tuple> {
     fn get_schema(&self) -> Schema;
+    /// Takes the error that ended the iteration early, since a failed read stops an operator
+    /// the same way running out of tuples does.
+    fn take_err(&mut self) -> Option<Error>;
 }
 
 impl<T: Table> Iterator for TableIter<T> {
 
     type Item = Tuple;
 
+    /// Pins the current page only while one tuple is read from it, so a paused scan holds no frame.
     fn next(&mut self) -> Option<Self::Item>{
-        let Some(block_num) = self.block_num else { return None; };
-        if self.page.is_null() {
-            self.page = self.buf.fetch(((self.table.inode().data_ino as u128) << 64) | (block_num & SET_64) as u128) as *const RwLock<Page>;
-        } else {
-            let page_id;
-            let page = unsafe {self.page.as_ref().unwrap().read().unwrap()};
-            page_id = page.page_id;
-            drop(page);
-            if page_id != Some(((self.table.inode().data_ino as u128) << 64) | (block_num & SET_64) as u128) {
-                self.page = self.buf.fetch(((self.table.inode().data_ino as u128) << 64) | (block_num & SET_64) as u128) as *const RwLock<Page>;
+        let buf = Arc::clone(&self.buf);
+        loop {
+            let block_num = self.block_num?;
+            let page_id = ((self.table.inode().data_ino as u128) << 64) | (block_num & SET_64) as u128;
+            if self.tup_idx == 0 {
+                // a failed read ahead is left to the fetch of the block itself to report
+                if let Some(blocks) = self.read_ahead.advance(block_num) { let _ = buf.prefetch(page_id - block_num as u128 + blocks.start as u128, blocks.count(), &self.strategy); }
             }
-        }
-        let tup = unsafe {PageIter::iter(self.page.as_ref().unwrap(), &self.table.schema()).nth(self.tup_idx as usize)};
-        match tup {
-            Ok(Some(t)) => {
-                self.tup_idx += 1;
-                Some(t)
-            },
-            Ok(None) => {
-                self.tup_idx += 1;
-                self.next()
-            },
-            Err(_) => {
-                if (self.on_page_end)(self) { return None; }
-                self.page = self.buf.fetch(((self.table.inode().data_ino as u128) << 64) | (block_num & SET_64) as u128) as *const RwLock<Page>;
-                self.next()
+            let tup = buf.fetch_with(page_id, &self.strategy)
+                .and_then(|page| match PageIter::iter(&page, &self.table.schema(), &buf).nth(self.tup_idx as usize) {
+                    Err(Error::PageError(PageError::OutOfBounds)) => {
+                        if (self.on_page_end)(self, &page.read().unwrap()) { self.block_num = None; }
+                        Ok(None)
+                    },
+                    Ok(t) => {
+                        self.tup_idx += 1;
+                        Ok(t)
+                    },
+                    Err(e) => Err(e)
+                });
+            match tup {
+                Ok(Some(t)) => return Some(t),
+                Ok(None) => continue,
+                Err(e) => {
+                    self.err = Some(e);
+                    self.block_num = None;
+                    return None;
+                }
             }
         }
     }
 }
 
+/// A column of a row with spilled strings, as stored in the row.
+enum Stored<'a> {
+    Inline(&'a [u8]),
+    Spilled { len: usize, block: u32 }
+}
+
+/// Splits a row with spilled strings into its columns.
+fn stored_columns<'a>(schema: &Schema, bytes: &'a [u8]) -> Result<Vec<Stored<'a>>, Error> {
+    let invalid = || Error::PageError(PageError::InvalidTuple);
+    let mut pos = 0;
+    schema.iter().map(|(_, ty)| {
+        let rest = bytes.get(pos..).ok_or_else(invalid)?;
+        let (col, siz) = match ty.serialized_size() {
+            Some(siz) => (Stored::Inline(rest.get(..siz).ok_or_else(invalid)?), siz),
+            None => {
+                let len = u32::from_le_bytes(rest.get(..TEXT_PREFIX).ok_or_else(invalid)?.try_into().unwrap());
+                if len & SPILLED == 0 {
+                    let siz = TEXT_PREFIX + len as usize;
+                    (Stored::Inline(rest.get(..siz).ok_or_else(invalid)?), siz)
+                } else {
+                    let block = u32::from_le_bytes(rest.get(TEXT_PREFIX..SPILL_PTR).ok_or_else(invalid)?.try_into().unwrap());
+                    (Stored::Spilled { len: (len & !SPILLED) as usize, block }, SPILL_PTR)
+                }
+            }
+        };
+        pos += siz;
+        Ok(col)
+    }).collect()
+}
+
 pub struct PageIter<'a> {
     tup_idx: u16,
-    tup_siz: u16,
     schema: Schema,
-    page: &'a RwLock<Page>
+    page: &'a RwLock<Page>,
+    buf: &'a PageBuffer
 }
 
 impl PageIter<'_> {
-    pub fn iter<'a>(page: &'a RwLock<Page>, schema: &Schema) -> PageIter<'a> {
-        let tup_siz = schema
-            .iter()
-            .map(|(_, x)| x.serialized_size())
-            .reduce(|acc, siz| acc + siz).unwrap() as u16;
+    pub fn iter<'a>(page: &'a RwLock<Page>, schema: &Schema, buf: &'a PageBuffer) -> PageIter<'a> {
+        PageIter { tup_idx: 0, schema: schema.to_vec(), page, buf }
+    }
+
+    /// Reads the tuple at `tup_idx` without holding the page lock while overflow blocks are fetched.
+    fn read(&self) -> Result<Option<Tuple>, Error> {
+        let (page_id, bytes) = {
+            let page = self.page.read().unwrap();
+            (page.page_id, page.read(self.tup_idx)?)
+        };
+        let Some(bytes) = bytes else { return Ok(None); };
+        let row = match bytes.first() {
+            Some(&ROW_INLINE) => bytes[1..].to_vec(),
+            Some(&ROW_OVERFLOW) => self.unspill(page_id.ok_or(Error::PageError(PageError::NoBlock))?, &bytes[1..])?,
+            Some(&ROW_CHUNK) => return Ok(None),
+            _ => return Err(Error::PageError(PageError::InvalidTuple))
+        };
+        self.decode(&row).map(Some)
+    }
+
+    /// Puts the spilled strings of a row back in place by following their overflow chains.
+    fn unspill(&self, page_id: u128, bytes: &[u8]) -> Result<Vec<u8>, Error> {
+        let mut row = vec![];
+        for col in stored_columns(&self.schema, bytes)? {
+            match col {
+                Stored::Inline(bytes) => row.extend(bytes),
+                Stored::Spilled { len, block } => {
+                    row.extend((len as u32).to_le_bytes());
+                    row.extend(self.read_chain(page_id, block, len)?);
+                }
+            }
+        }
+        Ok(row)
+    }
 
-        PageIter { tup_idx: 0, tup_siz, schema: schema.to_vec(), page }
+    fn read_chain(&self, page_id: u128, mut block_num: u32, len: usize) -> Result<Vec<u8>, Error> {
+        let mut data = Vec::with_capacity(len);
+        loop {
+            let page = self.buf.fetch(((page_id >> 64) << 64) | block_num as u128)?;
+            let p = page.read().unwrap();
+            match p.read(0)? {
+                Some(piece) if piece.first() == Some(&ROW_CHUNK) => data.extend(&piece[1..]),
+                _ => return Err(Error::PageError(PageError::InvalidTuple))
+            }
+            if data.len() == len { return Ok(data); }
+            let (true, Some(next)) = (data.len() < len, p.get_next()) else {return Err(Error::PageError(PageError::InvalidTuple))};
+            block_num = next;
+        }
+    }
+
+    /// Decodes the columns one after another, each starting where the previous one ended.
+    fn decode(&self, bytes: &[u8]) -> Result<Tuple, Error> {
+        let mut pre = 0;
+        self.schema
+            .iter()
+            .map(|(_, x)| {
+                let (datum, siz) = x.decode(&bytes[pre..]).ok_or(Error::PageError(PageError::InvalidTuple))?;
+                pre += siz;
+                Ok(datum)
+            })
+            .collect()
     }
 
     pub fn nth(&mut self, n: usize) -> Result<Option<Tuple>, Error> {
         self.tup_idx += n as u16;
-        let bytes = self.page.read().unwrap().read(self.tup_idx, self.tup_siz)?;
-
-        let Some(bytes) = bytes else { return Ok(None); };
-        
-        Ok(Some(self.schema
-                .iter()
-                .scan(0, |pre, (_, x)| {
-                    let siz = x.serialized_size();
-                    *pre += siz;
-                    Some(x.decode(&bytes[(*pre-siz) as usize ..*pre as usize]).expect("could not decode tuple element"))
-                })
-                .collect()))
+        self.read()
     }
 }
 
 impl Iterator for PageIter<'_> {
-    type Item = Tuple;
-    
+    type Item = Result<Tuple, Error>;
+
+    /// Skips deleted tuples and ends after the last slot, any other failure is handed on.
     fn next(&mut self) -> Option<Self::Item> {
-        let Ok(bytes)= self.page.read().unwrap().read(self.tup_idx, self.tup_siz) else { return None; };
+        let tuple = self.read();
         self.tup_idx += 1;
-
-        let Some(bytes) = bytes else { return self.next(); };
-
-        Some(self.schema
-                .iter()
-                .scan(0, |pre, (_, x)| {
-                    let siz = x.serialized_size();
-                    *pre += siz;
-                    Some(x.decode(&bytes[(*pre-siz) as usize ..*pre as usize]).expect("could not decode tuple element"))
-                })
-                .collect())
+        match tuple {
+            Ok(Some(t)) => Some(Ok(t)),
+            Ok(None) => self.next(),
+            Err(Error::PageError(PageError::OutOfBounds)) => None,
+            Err(e) => Some(Err(e))
+        }
     }
 }
 
@@ -323,33 +840,320 @@ impl Iterator for PageIter<'_> {
 mod tests {
     use std::{vec, sync::Arc};
 
-    use crate::{buffer::{tuple::{PageIter, Table, PageBuffer}, Buff}, storage::folder::Folder};
+    use std::{fs::OpenOptions, io::{Seek, SeekFrom, Write}};
 
-    use super::{RowTable, DatumTypes, TupleOps, Datum};
+    use crate::{buffer::{tuple::{PageIter, Table, PageBuffer}, page::{Reclaimed, TupleCRUD, ROW_INLINE}, Buff}, storage::{folder::test_folder, utils::delete_file, BLCKSIZ, DATSIZ, LOCSIZ}, error::{Error, PageError}};
+
+    use super::{RowTable, DatumTypes, TupleOps, Datum, Hash};
 
     
     #[test]
     fn test_table_create() {
         let t_name = "test_table_create".to_string();
-        let f = Arc::new(Folder::new().unwrap());
+        let f = test_folder("tuple_table_create");
         let t = RowTable::create(Arc::clone(&f), &t_name, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
-        assert_eq!(t, RowTable { inode: t.inode(), temp: false, num_blocks: 0, schema: vec![(t_name.clone()+"."+"a", DatumTypes::Int), (t_name.clone()+"."+"b", DatumTypes::Int)]});
+        assert_eq!(t, RowTable { inode: t.inode(), temp: false, num_blocks: 0, schema: vec![(t_name.clone()+"."+"a", DatumTypes::Int), (t_name.clone()+"."+"b", DatumTypes::Int)], free: vec![], db: Some(f.db()), read_only: true});
     }
 
     #[test]
     fn test_page_itr_nth() {
         let id = "page_itr_nth".to_string();
-        let f = Arc::new(Folder::new().unwrap());
-        let mut t = RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int), ("b".to_string(), DatumTypes::Int)]).unwrap();
-        let buf = Arc::new(PageBuffer::new(10));
-        let mut tuple = vec![Datum::Int(10), Datum::Int(20)];
-        t.add(Arc::clone(&buf), tuple).unwrap();
-        tuple = vec![Datum::Int(10), Datum::Int(30)];
-        t.add(Arc::clone(&buf), tuple).unwrap();
-        let bind = buf.fetch((t.inode().data_ino as u128) << 64);
-        let mut itr = PageIter::iter(bind, &t.schema);
-
-        assert_eq!(itr.nth(1).unwrap(), Some(vec![Datum::Int(10), Datum::Int(30)]));
-        assert!(itr.nth(1).is_err());
-    }
-}
\ No newline at end of file
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int), ("b".to_string(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            let mut tuple = vec![Datum::Int(10), Datum::Int(20)];
+            t.add(Arc::clone(&buf), tuple).unwrap();
+            tuple = vec![Datum::Int(10), Datum::Int(30)];
+            t.add(Arc::clone(&buf), tuple).unwrap();
+            let bind = buf.fetch((t.inode().data_ino as u128) << 64).unwrap();
+            let mut itr = PageIter::iter(&bind, &t.schema, &buf);
+
+            assert_eq!(itr.nth(1).unwrap(), Some(vec![Datum::Int(10), Datum::Int(30)]));
+            assert!(itr.nth(1).is_err());
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_overflow() {
+        let id = "table_overflow".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int), ("b".to_string(), DatumTypes::Text), ("c".to_string(), DatumTypes::Text)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            let rows = vec![
+                vec![Datum::Int(1), Datum::Text("x".repeat(20000)), Datum::Text("short".into())],
+                vec![Datum::Int(2), Datum::Text("y".repeat(5000)), Datum::Text("z".repeat(5000))]
+            ];
+            for row in rows.iter() {
+                t.add(Arc::clone(&buf), row.to_vec()).unwrap();
+            }
+
+            // three overflow blocks for the first long string, the first row shares the last one;
+            // the second row spills one string to a fourth and keeps the other inline in a fifth
+            assert_eq!(t.num_blocks, 5);
+            assert_eq!(t.iter(Arc::clone(&buf)).collect::<Vec<_>>(), rows);
+            assert!(matches!(t.update(Arc::clone(&buf), 4, 1, rows[0].clone()), Err(Error::PageError(PageError::OutOfBounds))));
+            assert_eq!(t.num_blocks, 5);
+
+            t.vacuum(Arc::clone(&buf)).unwrap();
+            let mut itr = t.iter(Arc::clone(&buf));
+            assert_eq!(itr.by_ref().collect::<Vec<_>>(), rows);
+            assert!(itr.err.is_none());
+            Ok(())
+        }).unwrap();
+
+        RowTable::create(Arc::clone(&f), "wide", (0..3000).map(|i| (format!("c{i}"), DatumTypes::Int)).collect()).unwrap();
+        assert!(matches!(f.update_table("wide", |wide: &mut RowTable| wide.add(Arc::clone(&buf), (0..3000).map(Datum::Int).collect())), Err(Error::PageError(PageError::OutOfBounds))));
+        assert_eq!(RowTable::new(Arc::clone(&f), "wide").unwrap().num_blocks, 0);
+    }
+
+    #[test]
+    fn test_overflow_reuse() {
+        let id = "table_overflow_reuse".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int), ("b".to_string(), DatumTypes::Text)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            t.add(Arc::clone(&buf), vec![Datum::Int(0), Datum::Text("x".repeat(20000))]).unwrap();
+            assert_eq!(t.num_blocks, 3);
+            assert!(t.delete(Arc::clone(&buf), 0, 0).is_err());
+
+            // every update releases the chain of the string it replaces for the next one to take
+            for i in 1..10 {
+                t.update(Arc::clone(&buf), 2, 1, vec![Datum::Int(i), Datum::Text(i.to_string().repeat(20000))]).unwrap();
+            }
+            assert!(t.num_blocks <= 7);
+            assert_eq!(t.iter(Arc::clone(&buf)).collect::<Vec<_>>(), vec![vec![Datum::Int(9), Datum::Text("9".repeat(20000))]]);
+
+            t.delete(Arc::clone(&buf), 2, 1).unwrap();
+            t.vacuum(Arc::clone(&buf)).unwrap();
+            let blocks = t.num_blocks;
+            t.add(Arc::clone(&buf), vec![Datum::Int(10), Datum::Text("y".repeat(20000))]).unwrap();
+            assert_eq!(t.num_blocks, blocks);
+            assert_eq!(t.iter(buf).collect::<Vec<_>>(), vec![vec![Datum::Int(10), Datum::Text("y".repeat(20000))]]);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_failed_insert_rolls_back() {
+        let id = "failed_insert".to_string();
+        let f = test_folder(&id);
+        let db = f.db();
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int), ("b".to_string(), DatumTypes::Text), ("c".to_string(), DatumTypes::Text)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            t.add(Arc::clone(&buf), vec![Datum::Int(0), Datum::Text("x".repeat(20000)), Datum::Text(String::new())]).unwrap();
+            t.delete(Arc::clone(&buf), 2, 1).unwrap();
+            t.vacuum(Arc::clone(&buf)).unwrap();
+            assert_eq!(t.num_blocks, 3);
+
+            // the first string goes to the blocks the deleted row left empty, the block for the
+            // second one is logged but cannot be appended to the deleted file
+            delete_file(&db, &t.inode().data_ino.to_string()).unwrap();
+            assert!(t.add(Arc::clone(&buf), vec![Datum::Int(1), Datum::Text("y".repeat(20000)), Datum::Text("z".repeat(20000))]).is_err());
+            // its pieces are gone again and the blocks are still there for the next string
+            for block_num in 0..3 {
+                assert_eq!(buf.fetch(t.page_id(block_num)).unwrap().read().unwrap().read(0).unwrap(), None);
+                assert!(t.free[block_num as usize] as usize + LOCSIZ as usize >= DATSIZ);
+            }
+            Ok(())
+        }).unwrap();
+        // nothing is left in flight to keep the log from being truncated
+        db.wal().truncate(db.wal().next_lsn()).unwrap();
+        assert!(db.wal().records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_table_update_delete() {
+        let id = "table_update_delete".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            for i in 0..3 {
+                t.add(Arc::clone(&buf), vec![Datum::Int(i)]).unwrap();
+            }
+            t.update(Arc::clone(&buf), 0, 1, vec![Datum::Int(10)]).unwrap();
+            t.delete(Arc::clone(&buf), 0, 2).unwrap();
+            assert!(t.delete(Arc::clone(&buf), 0, 2).is_err());
+
+            assert_eq!(t.iter(buf).collect::<Vec<_>>(), vec![vec![Datum::Int(0)], vec![Datum::Int(10)]]);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_free_space_reuse() {
+        let id = "free_space_reuse".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int), ("b".to_string(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            for i in 0..900 {
+                t.add(Arc::clone(&buf), vec![Datum::Int(i), Datum::Int(i)]).unwrap();
+            }
+            assert_eq!(t.num_blocks, 2);
+            let free = t.free[0];
+            for i in 0..10 {
+                t.delete(Arc::clone(&buf), 0, i).unwrap();
+            }
+            assert_eq!(t.free[0], free + 10*9);
+            assert_eq!(f.read_table::<RowTable>(&id).unwrap().unwrap().free, t.free);
+            for i in 0..10 {
+                t.add(Arc::clone(&buf), vec![Datum::Int(i), Datum::Int(i)]).unwrap();
+            }
+
+            assert_eq!(t.num_blocks, 2);
+            assert_eq!(t.free[0], free);
+            assert_eq!(t.iter(buf).count(), 900);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_vacuum() {
+        let id = "vacuum".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            for i in 0..4 {
+                t.add(Arc::clone(&buf), vec![Datum::Int(i)]).unwrap();
+            }
+            t.delete(Arc::clone(&buf), 0, 1).unwrap();
+            t.delete(Arc::clone(&buf), 0, 3).unwrap();
+
+            assert_eq!(t.vacuum(Arc::clone(&buf)).unwrap(), Reclaimed { bytes: 2*5 + 4, slots: 1 });
+            assert_eq!(t.vacuum(Arc::clone(&buf)).unwrap(), Reclaimed::default());
+            assert_eq!(t.iter(buf).collect::<Vec<_>>(), vec![vec![Datum::Int(0)], vec![Datum::Int(2)]]);
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_page() {
+        let id = "corrupted_page".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            t.add(Arc::clone(&buf), vec![Datum::Int(10)]).unwrap();
+            buf.flush().unwrap();
+            let mut file = OpenOptions::new().write(true).open(f.db().path(&t.inode().data_ino.to_string())).unwrap();
+            file.seek(SeekFrom::Start(BLCKSIZ as u64 - 1)).unwrap();
+            file.write_all(&[0xAB]).unwrap();
+
+            let page_id = (t.inode().data_ino as u128) << 64;
+            assert!(matches!(buf.fetch(page_id), Err(Error::PageError(PageError::Corrupted { page_id: p })) if p == page_id));
+            let mut itr = t.iter(buf);
+            assert_eq!(itr.next(), None);
+            assert!(itr.err.is_some());
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_short_read() {
+        let id = "short_read".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            t.add(Arc::clone(&buf), vec![Datum::Int(10)]).unwrap();
+            buf.flush().unwrap();
+            let path = f.db().path(&t.inode().data_ino.to_string());
+            OpenOptions::new().write(true).open(&path).unwrap().set_len(BLCKSIZ as u64 / 2).unwrap();
+
+            let page_id = (t.inode().data_ino as u128) << 64;
+            assert!(matches!(buf.fetch(page_id), Err(Error::PageError(PageError::ShortRead { page_id: p })) if p == page_id));
+            delete_file(&f.db(), &t.inode().data_ino.to_string()).unwrap();
+            assert!(matches!(buf.fetch(page_id), Err(Error::Io(_))));
+            let mut itr = t.iter(buf);
+            assert_eq!(itr.next(), None);
+            assert!(matches!(itr.err, Some(Error::Io(_))));
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_undecodable_tuple() {
+        let id = "undecodable_tuple".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            t.add(Arc::clone(&buf), vec![Datum::Int(10)]).unwrap();
+            let page = buf.fetch((t.inode().data_ino as u128) << 64).unwrap();
+            page.write().unwrap().write(&[ROW_INLINE, 1]).unwrap();
+            drop(page);
+
+            let mut itr = t.iter(buf);
+            assert_eq!(itr.next(), Some(vec![Datum::Int(10)]));
+            assert_eq!(itr.next(), None);
+            assert!(matches!(itr.err, Some(Error::PageError(PageError::InvalidTuple))));
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_text() {
+        let id = "table_text".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::Int), ("b".to_string(), DatumTypes::Varchar(5)), ("c".to_string(), DatumTypes::Text)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            let rows = vec![
+                vec![Datum::Int(1), Datum::Text("héllo".into()), Datum::Text(String::new())],
+                vec![Datum::Int(2), Datum::Text("a,'b".into()), Datum::Text("x".repeat(20000))]
+            ];
+            for row in &rows {
+                t.add(Arc::clone(&buf), row.clone()).unwrap();
+            }
+            assert!(matches!(t.add(Arc::clone(&buf), vec![Datum::Int(3), Datum::Text("toolong".into()), Datum::Text(String::new())]), Err(Error::TypeMismatch)));
+            assert!(matches!(t.add(Arc::clone(&buf), vec![Datum::Int(3), Datum::Int(4), Datum::Text(String::new())]), Err(Error::TypeMismatch)));
+            assert_eq!(t.iter(buf).collect::<Vec<_>>(), rows);
+
+            assert_eq!(DatumTypes::parse("varchar(12)").unwrap(), DatumTypes::Varchar(12));
+            assert_eq!(DatumTypes::parse("TEXT").unwrap(), DatumTypes::Text);
+            assert!(DatumTypes::parse("varchar(x)").is_err());
+            assert!(DatumTypes::Varchar(3).compatible(&DatumTypes::Text));
+            assert!(!DatumTypes::Int.compatible(&DatumTypes::Text));
+            Ok(())
+        }).unwrap();
+    }
+
+    #[test]
+    fn test_numeric_types() {
+        let id = "table_numeric_types".to_string();
+        let f = test_folder(&id);
+        RowTable::create(Arc::clone(&f), &id, vec![("a".to_string(), DatumTypes::BigInt), ("b".to_string(), DatumTypes::SmallInt), ("c".to_string(), DatumTypes::Double), ("d".to_string(), DatumTypes::Boolean)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&id, |t: &mut RowTable| {
+            let rows = vec![
+                vec![Datum::BigInt(i64::MAX), Datum::SmallInt(i16::MIN), Datum::Double(0.1 + 0.2), Datum::Bool(true)],
+                vec![Datum::BigInt(-1), Datum::SmallInt(7), Datum::Double(1e300), Datum::Bool(false)]
+            ];
+            for row in &rows {
+                t.add(Arc::clone(&buf), row.clone()).unwrap();
+            }
+            assert!(matches!(t.add(Arc::clone(&buf), vec![Datum::Int(1), Datum::SmallInt(1), Datum::Double(1.0), Datum::Bool(true)]), Err(Error::TypeMismatch)));
+            assert_eq!(t.iter(buf).collect::<Vec<_>>(), rows);
+
+            assert!(Datum::SmallInt(70).join_eq(&Datum::BigInt(70)));
+            assert_eq!(Datum::SmallInt(70).hash(), Datum::BigInt(70).hash());
+            assert_ne!(Datum::BigInt(1 << 32).hash(), Datum::BigInt(0).hash());
+            assert_eq!(Datum::Int(-7).hash(), Datum::BigInt(-7).hash());
+            assert!(!Datum::Float(0.5).join_eq(&Datum::Double(0.5)));
+            assert_eq!(Datum::Double(-0.0).hash(), Datum::Double(0.0).hash());
+            assert_eq!(Datum::Float(-0.0).hash(), Datum::Float(0.0).hash());
+            assert_ne!(Datum::Double(-1.5).hash(), Datum::Double(-2.5).hash());
+            assert_ne!(Datum::Double(70000.25).hash(), Datum::Double(90000.75).hash());
+            assert!(!Datum::Int(1).join_eq(&Datum::Double(1.0)));
+            assert!(!Datum::Bool(true).join_eq(&Datum::Int(1)));
+            Ok(())
+        }).unwrap();
+    }
+}
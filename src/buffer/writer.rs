@@ -0,0 +1,81 @@
+use std::{sync::{Arc, Weak, Mutex, Condvar}, thread::{self, JoinHandle}, time::Duration};
+
+use crate::error::Error;
+
+use super::tuple::PageBuffer;
+
+/// Background thread writing dirty pages back ahead of eviction, so a query that needs a frame
+/// rarely pays for the write itself.
+///
+/// Every `interval` it writes up to `batch` dirty pages that nobody holds locked. It stops when
+/// dropped or when the pool goes away; a failed write leaves the page dirty for the next round,
+/// is counted in the pool's stats and is kept for `take_err`.
+pub struct Writer {
+    stop: Arc<(Mutex<bool>, Condvar)>,
+    err: Arc<Mutex<Option<Error>>>,
+    handle: Option<JoinHandle<()>>
+}
+
+impl Writer {
+    pub fn start(buf: &Arc<PageBuffer>, interval: Duration, batch: usize) -> Self {
+        let stop = Arc::new((Mutex::new(false), Condvar::new()));
+        let buf: Weak<PageBuffer> = Arc::downgrade(buf);
+        let signal = Arc::clone(&stop);
+        let err = Arc::new(Mutex::new(None));
+        let failed = Arc::clone(&err);
+        let handle = thread::spawn(move || {
+            let (stopped, cvar) = &*signal;
+            let mut guard = stopped.lock().unwrap();
+            loop {
+                guard = cvar.wait_timeout(guard, interval).unwrap().0;
+                if *guard { return; }
+                let Some(buf) = buf.upgrade() else { return; };
+                if let Err(e) = buf.write_idle(batch) { *failed.lock().unwrap() = Some(e); }
+            }
+        });
+        Self { stop, err, handle: Some(handle) }
+    }
+
+    /// The last error a round ran into since this was last called.
+    pub fn take_err(&self) -> Option<Error> {
+        self.err.lock().unwrap().take()
+    }
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        let (stopped, cvar) = &*self.stop;
+        *stopped.lock().unwrap() = true;
+        cvar.notify_one();
+        if let Some(handle) = self.handle.take() { let _ = handle.join(); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::{Duration, Instant}, thread};
+
+    use crate::{buffer::{tuple::{PageBuffer, RowTable, DatumTypes, Datum, TupleOps, Table}, Buff}, storage::{folder::test_folder, disk_manager::read_block}};
+
+    use super::Writer;
+
+    #[test]
+    fn test_background_writer() {
+        let f = test_folder("background_writer");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        let t = RowTable::create(Arc::clone(&f), "a", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        f.update_table("a", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(7)])).unwrap();
+        let page_id = (t.inode().data_ino as u128) << 64;
+        assert!(buf.fetch(page_id).unwrap().read().unwrap().is_dirty());
+
+        let writer = Writer::start(&buf, Duration::from_millis(5), 8);
+        let start = Instant::now();
+        while buf.fetch(page_id).unwrap().read().unwrap().is_dirty() {
+            assert!(start.elapsed() < Duration::from_secs(5), "page was never written back");
+            thread::sleep(Duration::from_millis(5));
+        }
+        drop(writer);
+        let buffered = buf.fetch(page_id).unwrap().read().unwrap().block.as_ref().unwrap().lower;
+        assert_eq!(read_block(&f.db(), page_id).unwrap().lower, buffered);
+    }
+}
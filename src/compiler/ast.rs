@@ -11,17 +11,17 @@ pub struct Node {
 }
 
 pub fn parse_join(input: &str) -> IResult<&str, Option<Node>> {
-    if input == "" { return Ok((input, None))}
+    if input.is_empty() { return Ok((input, None))}
     let (input, _op) = preceded(space1, tag_no_case("JOIN"))(input)?;
     let (input, name) = preceded(space1, alpha1)(input)?;
     let (input, (f1, _alop, f2)) = tuple((preceded(delimited(space1, tag_no_case("ON"), space1), separated_pair(alpha1, tag("."), alpha1)), delimited(space0, tag("="), space0), separated_pair(alpha1, tag("."), alpha1)))(input)?;
-    Ok((input, Some(Node { table: name.into(), cols: vec![], pred: Some(Predicate::Equal(Equal { l: Field { table: f1.0.into(), col: f1.1.into() }, r: Field { table: f2.0.into(), col: f2.1.into() }})), join: parse_join(input)?.1.map(|n| Box::new(n))})))
+    Ok((input, Some(Node { table: name.into(), cols: vec![], pred: Some(Predicate::Equal(Equal { l: Field { table: f1.0.into(), col: f1.1.into() }, r: Field { table: f2.0.into(), col: f2.1.into() }})), join: parse_join(input)?.1.map(Box::new)})))
 }
 
 pub fn parse_ast(input: &str) -> IResult<&str, Node> {
     let (input, l_name) = preceded(space0, alpha1)(input)?;
     let (input, r) = parse_join(input)?;
-    Ok((input, Node { table: l_name.into(), cols: vec![], pred: None, join: r.map(|n| Box::new(n))}))
+    Ok((input, Node { table: l_name.into(), cols: vec![], pred: None, join: r.map(Box::new)}))
 }
 
 #[cfg(test)]
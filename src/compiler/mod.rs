@@ -1,18 +1,22 @@
 use std::sync::Arc;
 
-use nom::{bytes::complete::{tag_no_case, tag}, IResult, sequence::{preceded, delimited, separated_pair}, character::complete::{alpha1, alphanumeric1}, multi::separated_list1, branch::alt};
+use nom::{bytes::complete::{tag_no_case, tag, is_not, take_while1}, IResult, sequence::{preceded, delimited, separated_pair, pair}, character::complete::{alpha1, digit1}, multi::{separated_list1, many0}, branch::alt, combinator::{opt, recognize, value, map}};
 
-use crate::{buffer::tuple::{RowTable, DatumTypes, Table, Datum, TupleOps, Tuple, PageBuffer}, operator::{Select, SelectIter}, error::Error, storage::folder::Folder};
+use crate::{buffer::tuple::{RowTable, DatumTypes, Table, Datum, TupleOps, Tuple, PageBuffer, Operator}, operator::{Select, SelectIter}, error::Error, storage::folder::Folder};
 
 pub mod ast;
 pub mod semantic;
 pub mod generator;
 
-pub fn parse_create_table(input: &str) -> IResult<&str, impl '_ + Fn(Arc<PageBuffer>, Arc<Folder>) -> Result<(), Error>> {
+/// What a parsed statement runs against the buffer pool and the catalog.
+pub type Exec<'a, R> = Box<dyn 'a + Fn(Arc<PageBuffer>, Arc<Folder>) -> Result<R, Error>>;
+
+pub fn parse_create_table(input: &str) -> IResult<&str, Exec<'_, ()>> {
     let (input, name) = preceded(tag_no_case("CREATE TABLE "), alpha1)(input)?;
-    let (input, schema) = delimited(tag("("), separated_list1(tag(","), separated_pair(alpha1, tag(" "), alpha1)), tag(")"))(input)?;
+    let typ = recognize(pair(alpha1, opt(delimited(tag("("), digit1, tag(")")))));
+    let (input, schema) = delimited(tag("("), separated_list1(tag(","), separated_pair(alpha1, tag(" "), typ)), tag(")"))(input)?;
     
-    Ok((input, move |_buf: Arc<PageBuffer>, f: Arc<Folder>| {
+    Ok((input, Box::new(move |_buf: Arc<PageBuffer>, f: Arc<Folder>| {
         let mut err = Ok(());
         let schema = schema.iter().scan(&mut err, |err, (col, typ)| {
             match DatumTypes::parse(typ) {
@@ -27,54 +31,289 @@ pub fn parse_create_table(input: &str) -> IResult<&str, impl '_ + Fn(Arc<PageBuf
         RowTable::create(Arc::clone(&f), name, schema)?;
         RowTable::new(Arc::clone(&f), name)?;
         Ok(())
-    }))
+    })))
 }
 
-pub fn parse_select(input: &str) -> IResult<&str, impl '_ + Fn(Arc<PageBuffer>, Arc<Folder>) -> Result<SelectIter, Error>> {
+pub fn parse_select(input: &str) -> IResult<&str, Exec<'_, SelectIter>> {
     let (input, _cols) = preceded(tag_no_case("SELECT "), separated_list1(tag(","), alt((tag("*"), alpha1))))(input)?;
     let (input, name) = preceded(tag_no_case(" FROM "), alpha1)(input)?;
 
-    Ok((input, move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
+    Ok((input, Box::new(move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
         let table = RowTable::new(Arc::clone(&f), name)?;
         Ok(Select::new(table, buf, |_| true).into_iter())
-    }))
+    })))
+}
+
+/// Virtual tables over the buffer pool: `sys_buffer` holds one row of fetches, hits, misses,
+/// evictions, writes and pages read ahead, `sys_buffer_tables` one row of data file inode and resident pages per file.
+pub fn parse_system_select(input: &str) -> IResult<&str, Exec<'_, Vec<Tuple>>> {
+    let (input, name) = preceded(tag_no_case("SELECT * FROM "), alt((tag_no_case("sys_buffer_tables"), tag_no_case("sys_buffer"))))(input)?;
+
+    Ok((input, Box::new(move |buf: Arc<PageBuffer>, _f: Arc<Folder>| {
+        let stats = buf.stats();
+        let int = |v: u64| Datum::BigInt(v as i64);
+        if name.eq_ignore_ascii_case("sys_buffer") {
+            return Ok(vec![vec![int(stats.fetches), int(stats.hits), int(stats.misses), int(stats.evictions), int(stats.writes), int(stats.prefetched)]]);
+        }
+        let mut files: Vec<_> = stats.resident.into_iter().collect();
+        files.sort();
+        Ok(files.into_iter().map(|(ino, pages)| vec![int(ino), int(pages as u64)]).collect())
+    })))
+}
+
+/// A value in an INSERT.
+#[derive(Debug, Clone, PartialEq)]
+enum Literal<'a> {
+    /// A quoted string, in which `''` stands for one quote.
+    Quoted(String),
+    Bare(&'a str)
+}
+
+fn literal(input: &str) -> IResult<&str, Literal<'_>> {
+    let quoted = delimited(tag("'"), many0(alt((is_not("'"), value("'", tag("''"))))), tag("'"));
+    let bare = recognize(pair(opt(tag("-")), take_while1(|c: char| c.is_alphanumeric() || c == '.')));
+    alt((map(quoted, |parts| Literal::Quoted(parts.concat())), map(bare, Literal::Bare)))(input)
 }
 
-pub fn parse_insert(input: &str) -> IResult<&str, impl '_ + Fn(Arc<PageBuffer>, Arc<Folder>) -> Result<(), Error>>  {
+pub fn parse_insert(input: &str) -> IResult<&str, Exec<'_, ()>> {
     let (input, name) = preceded(tag_no_case("INSERT INTO "), alpha1)(input)?;
-    let (input, values) = preceded(tag_no_case(" VALUES"), delimited(tag("("), separated_list1(tag(","), alphanumeric1), tag(")")))(input)?;
+    let (input, values) = preceded(tag_no_case(" VALUES"), delimited(tag("("), separated_list1(tag(","), literal), tag(")")))(input)?;
     
-    Ok((input, move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
-        let mut table = RowTable::new(Arc::clone(&f), name)?;
-        let schema = table.schema();
-        let tup = schema.iter().zip(values.iter()).map(|((_, typ), inp)| {
-            match typ {
-                DatumTypes::Int => Datum::Int((*inp).parse::<i32>().unwrap()),
-                DatumTypes::Float => Datum::Float((*inp).parse::<f32>().unwrap())
-            }
-        }).collect();
-        table.add(buf, tup)
-    }))    
+    Ok((input, Box::new(move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
+        f.update_table(name, |table: &mut RowTable| {
+            let schema = table.schema();
+            let tup = schema.iter().zip(values.iter()).map(|((_, typ), inp)| {
+                match (typ, inp) {
+                    (DatumTypes::Int, Literal::Bare(v)) => v.parse::<i32>().map(Datum::Int).map_err(|_| Error::TypeMismatch),
+                    (DatumTypes::Float, Literal::Bare(v)) => v.parse::<f32>().map(Datum::Float).map_err(|_| Error::TypeMismatch),
+                    (DatumTypes::BigInt, Literal::Bare(v)) => v.parse::<i64>().map(Datum::BigInt).map_err(|_| Error::TypeMismatch),
+                    (DatumTypes::SmallInt, Literal::Bare(v)) => v.parse::<i16>().map(Datum::SmallInt).map_err(|_| Error::TypeMismatch),
+                    (DatumTypes::Double, Literal::Bare(v)) => v.parse::<f64>().map(Datum::Double).map_err(|_| Error::TypeMismatch),
+                    (DatumTypes::Boolean, Literal::Bare(v)) => v.to_ascii_lowercase().parse::<bool>().map(Datum::Bool).map_err(|_| Error::TypeMismatch),
+                    (DatumTypes::Varchar(_) | DatumTypes::Text, Literal::Quoted(v)) => Ok(Datum::Text(v.clone())),
+                    _ => Err(Error::TypeMismatch)
+                }
+            }).collect::<Result<Tuple, Error>>()?;
+            table.add(buf, tup)
+        })
+    })))
+}
+
+pub fn parse_vacuum(input: &str) -> IResult<&str, Exec<'_, Tuple>> {
+    let (input, name) = preceded(tag_no_case("VACUUM "), alpha1)(input)?;
+
+    Ok((input, Box::new(move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
+        let reclaimed = f.update_table(name, |table: &mut RowTable| table.vacuum(buf))?;
+        Ok(vec![Datum::BigInt(reclaimed.bytes as i64), Datum::BigInt(reclaimed.slots as i64)])
+    })))
+}
+
+pub fn parse_drop_table(input: &str) -> IResult<&str, Exec<'_, ()>> {
+    let (input, if_exists) = preceded(tag_no_case("DROP TABLE "), opt(tag_no_case("IF EXISTS ")))(input)?;
+    let (input, name) = alpha1(input)?;
+
+    Ok((input, Box::new(move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
+        match f.drop_table(name, &buf) {
+            Err(Error::TableDoesNotExist) if if_exists.is_some() => Ok(()),
+            r => r
+        }
+    })))
+}
+
+pub fn parse_truncate(input: &str) -> IResult<&str, Exec<'_, ()>> {
+    let (input, name) = preceded(tag_no_case("TRUNCATE "), preceded(opt(tag_no_case("TABLE ")), alpha1))(input)?;
+
+    Ok((input, Box::new(move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
+        f.truncate_table::<RowTable>(name, &buf)
+    })))
+}
+
+pub fn parse_rename_table(input: &str) -> IResult<&str, Exec<'_, ()>> {
+    let (input, name) = preceded(tag_no_case("ALTER TABLE "), alpha1)(input)?;
+    let (input, new_name) = preceded(tag_no_case(" RENAME TO "), alpha1)(input)?;
+
+    Ok((input, Box::new(move |_buf: Arc<PageBuffer>, f: Arc<Folder>| {
+        f.rename_table::<RowTable>(name, new_name)
+    })))
+}
+
+pub fn parse_checkpoint(input: &str) -> IResult<&str, Exec<'_, Tuple>> {
+    let (input, _) = tag_no_case("CHECKPOINT")(input)?;
+
+    Ok((input, Box::new(move |buf: Arc<PageBuffer>, f: Arc<Folder>| {
+        let written = f.checkpoint::<RowTable>(&buf)?;
+        Ok(vec![Datum::BigInt(written.pages as i64), Datum::BigInt(written.tables as i64)])
+    })))
 }
 
 pub fn parse(input: &str, buf: Arc<PageBuffer>, f: Arc<Folder>) -> Result<Option<Vec<Tuple>>, Error> {
-    let res = parse_create_table(input);
-    if res.is_ok() { (res.unwrap().1)(buf, Arc::clone(&f))?; return Ok(None); }
-    let res = parse_insert(input);
-    if res.is_ok() { (res.unwrap().1)(buf, Arc::clone(&f)).unwrap(); return Ok(None); }
-    let res = parse_select(input);
-    if res.is_ok() { return Ok(Some((res.unwrap().1)(buf, Arc::clone(&f))?.collect())); }
-    return Err(Error::ParseError)
+    if let Ok((_, exec)) = parse_create_table(input) { exec(buf, Arc::clone(&f))?; return Ok(None); }
+    if let Ok((_, exec)) = parse_insert(input) { exec(buf, Arc::clone(&f))?; return Ok(None); }
+    if let Ok((_, exec)) = parse_system_select(input) { return Ok(Some(exec(buf, Arc::clone(&f))?)); }
+    if let Ok((_, exec)) = parse_select(input) {
+        let mut rows = exec(buf, Arc::clone(&f))?;
+        let tuples = rows.by_ref().collect();
+        return rows.take_err().map_or(Ok(Some(tuples)), Err);
+    }
+    if let Ok((_, exec)) = parse_vacuum(input) { return Ok(Some(vec![exec(buf, Arc::clone(&f))?])); }
+    if let Ok((_, exec)) = parse_drop_table(input) { exec(buf, Arc::clone(&f))?; return Ok(None); }
+    if let Ok((_, exec)) = parse_truncate(input) { exec(buf, Arc::clone(&f))?; return Ok(None); }
+    if let Ok((_, exec)) = parse_rename_table(input) { exec(buf, Arc::clone(&f))?; return Ok(None); }
+    if let Ok((_, exec)) = parse_checkpoint(input) { return Ok(Some(vec![exec(buf, Arc::clone(&f))?])); }
+    Err(Error::ParseError)
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::compiler::parse_create_table;
+    use std::{sync::Arc, fs::OpenOptions, io::{Seek, SeekFrom, Write}};
+
+    use crate::{compiler::{parse_create_table, parse}, buffer::{tuple::{PageBuffer, RowTable, Table, TupleOps, Datum}, Buff}, storage::{folder::test_folder, utils::file_exists, BLCKSIZ}, error::{Error, PageError}};
 
     #[test]
     fn test_table_create() {
         let input = "create table Two(id INT,price INT)";
         assert!(parse_create_table(input).is_ok())
     }
-}
 
+    #[test]
+    fn test_vacuum() {
+        let f = test_folder("parse_vacuum");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Two(id INT,price INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(1,2)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(3,4)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        f.update_table("Two", |t: &mut RowTable| t.delete(Arc::clone(&buf), 0, 1)).unwrap();
+
+        assert_eq!(parse("vacuum Two", buf, f).unwrap(), Some(vec![vec![Datum::BigInt(4 + 9), Datum::BigInt(1)]]));
+    }
+
+    #[test]
+    fn test_drop_table() {
+        let f = test_folder("parse_drop_table");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Two(id INT,price INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(1,2)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        let inode = RowTable::new(Arc::clone(&f), "Two").unwrap().inode();
+
+        parse("drop table Two", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        // the dirty page of the dropped table is not written back to a deleted file
+        buf.flush().unwrap();
+        assert!(!file_exists(&f.db(), &inode.data_ino.to_string()));
+        assert!(matches!(parse("select * from Two", Arc::clone(&buf), Arc::clone(&f)), Err(Error::TableDoesNotExist)));
+        assert!(matches!(parse("drop table Two", Arc::clone(&buf), Arc::clone(&f)), Err(Error::TableDoesNotExist)));
+        parse("drop table if exists Two", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("create table Two(id INT)", buf, f).unwrap();
+    }
+
+    #[test]
+    fn test_select_corrupted() {
+        let f = test_folder("parse_select_corrupted");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Two(id INT,price INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(1,2)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        buf.flush().unwrap();
+        let inode = RowTable::new(Arc::clone(&f), "Two").unwrap().inode();
+        let mut file = OpenOptions::new().write(true).open(f.db().path(&inode.data_ino.to_string())).unwrap();
+        file.seek(SeekFrom::Start(BLCKSIZ as u64 - 1)).unwrap();
+        file.write_all(&[0xAB]).unwrap();
+
+        // the scan fails instead of answering with the rows read before the bad page
+        assert!(matches!(parse("select * from Two", buf, f), Err(Error::PageError(PageError::Corrupted { .. }))));
+    }
+
+    #[test]
+    fn test_truncate() {
+        let f = test_folder("parse_truncate");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Two(id INT,price INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(1,2)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+
+        parse("truncate table Two", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        assert_eq!(parse("select * from Two", Arc::clone(&buf), Arc::clone(&f)).unwrap(), Some(vec![]));
+        parse("insert into Two values(3,4)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        assert_eq!(parse("select * from Two", buf, f).unwrap(), Some(vec![vec![Datum::Int(3), Datum::Int(4)]]));
+    }
+
+    #[test]
+    fn test_rename_table() {
+        let f = test_folder("parse_rename_table");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Two(id INT,price INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("create table Three(id INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(1,2)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+
+        assert!(matches!(parse("alter table Two rename to Three", Arc::clone(&buf), Arc::clone(&f)), Err(Error::TableAlreadyExists)));
+        parse("alter table Two rename to Four", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        let t = RowTable::new(Arc::clone(&f), "Four").unwrap();
+        assert_eq!(t.schema().into_iter().map(|(col, _)| col).collect::<Vec<_>>(), vec!["Four.id", "Four.price"]);
+        assert!(RowTable::new(Arc::clone(&f), "Two").is_err());
+        assert_eq!(parse("select * from Four", buf, f).unwrap(), Some(vec![vec![Datum::Int(1), Datum::Int(2)]]));
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        let f = test_folder("parse_checkpoint");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Two(id INT,price INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("create table Three(id INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(1,2)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Three values(3)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+
+        assert_eq!(parse("checkpoint", Arc::clone(&buf), Arc::clone(&f)).unwrap(), Some(vec![vec![Datum::BigInt(2), Datum::BigInt(2)]]));
+        // nothing was in flight, so the checkpoint left no log behind
+        assert!(f.db().wal().records().unwrap().is_empty());
+        assert_eq!(parse("checkpoint", Arc::clone(&buf), Arc::clone(&f)).unwrap(), Some(vec![vec![Datum::BigInt(0), Datum::BigInt(2)]]));
+        parse("insert into Two values(3,4)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        assert!(!f.db().wal().records().unwrap().is_empty());
+        assert_eq!(parse("checkpoint", buf, Arc::clone(&f)).unwrap(), Some(vec![vec![Datum::BigInt(1), Datum::BigInt(2)]]));
+        assert!(f.db().wal().records().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_system_tables() {
+        let f = test_folder("parse_system_tables");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Two(id INT,price INT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(1,2)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Two values(3,4)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        let ino = RowTable::new(Arc::clone(&f), "Two").unwrap().inode().data_ino;
+
+        let stats = buf.stats();
+        assert_eq!((stats.fetches, stats.hits, stats.misses), (2, 1, 1));
+        assert_eq!(parse("select * from sys_buffer", Arc::clone(&buf), Arc::clone(&f)).unwrap(), Some(vec![vec![Datum::BigInt(2), Datum::BigInt(1), Datum::BigInt(1), Datum::BigInt(0), Datum::BigInt(0), Datum::BigInt(0)]]));
+        assert_eq!(parse("select * from sys_buffer_tables", Arc::clone(&buf), Arc::clone(&f)).unwrap(), Some(vec![vec![Datum::BigInt(ino as i64), Datum::BigInt(1)]]));
+        parse("checkpoint", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        assert_eq!(buf.stats().writes, 1);
+    }
+
+    #[test]
+    fn test_text_columns() {
+        let f = test_folder("compiler_text_columns");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table People(id INT,name VARCHAR(12),bio TEXT)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into People values(1,'Ann, Jr.','it''s me')", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into People values(-2,'','')", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        assert!(matches!(parse("insert into People values(3,'much too long a name','')", Arc::clone(&buf), Arc::clone(&f)), Err(Error::TypeMismatch)));
+        assert!(matches!(parse("insert into People values('3','a','b')", Arc::clone(&buf), Arc::clone(&f)), Err(Error::TypeMismatch)));
+        let text = |s: &str| Datum::Text(s.into());
+        assert_eq!(parse("select * from People", buf, f).unwrap(), Some(vec![
+            vec![Datum::Int(1), text("Ann, Jr."), text("it's me")],
+            vec![Datum::Int(-2), text(""), text("")]
+        ]));
+    }
+
+    #[test]
+    fn test_numeric_columns() {
+        let f = test_folder("compiler_numeric_columns");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        parse("create table Ledger(id BIGINT,qty SMALLINT,amount DOUBLE,paid BOOLEAN)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Ledger values(9000000000,-3,1234567.891,true)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        parse("insert into Ledger values(1,2,0.5,FALSE)", Arc::clone(&buf), Arc::clone(&f)).unwrap();
+        assert!(matches!(parse("insert into Ledger values(1,40000,0.5,true)", Arc::clone(&buf), Arc::clone(&f)), Err(Error::TypeMismatch)));
+        assert!(matches!(parse("insert into Ledger values(1,2,0.5,yes)", Arc::clone(&buf), Arc::clone(&f)), Err(Error::TypeMismatch)));
+        assert_eq!(parse("select * from Ledger", buf, f).unwrap(), Some(vec![
+            vec![Datum::BigInt(9000000000), Datum::SmallInt(-3), Datum::Double(1234567.891), Datum::Bool(true)],
+            vec![Datum::BigInt(1), Datum::SmallInt(2), Datum::Double(0.5), Datum::Bool(false)]
+        ]));
+    }
+}
@@ -13,9 +13,9 @@ impl TypeCheck for Node {
     fn check(&self, f: Arc<Folder>) -> Result<(), Error> {
         let t = RowTable::new(Arc::clone(&f), &self.table)?;
         if !self.cols.iter().all(|inp| t.schema().iter().find(|(col, _)| col == inp).is_some()) {return Err(Error::ColumnDoesNotExist);}
-        if self.pred.is_some() { self.pred.as_ref().unwrap().check(Arc::clone(&f))?; }
-        if self.join.is_some() { self.join.as_ref().unwrap().check(Arc::clone(&f))?; }
-        return Ok(())
+        if let Some(pred) = &self.pred { pred.check(Arc::clone(&f))?; }
+        if let Some(join) = &self.join { join.check(Arc::clone(&f))?; }
+        Ok(())
     }
 }
 
@@ -24,7 +24,7 @@ impl TypeCheck for Equal {
     fn check(&self, f: Arc<Folder>) -> Result<(), Error> {
         let ty1 = self.l.get_type(Arc::clone(&f))?;
         let ty2 = self.r.get_type(Arc::clone(&f))?;
-        if ty1 == ty2 { Ok(()) } else { Err(Error::TypeMismatch) }
+        if ty1.compatible(&ty2) { Ok(()) } else { Err(Error::TypeMismatch) }
     }
 }
 
@@ -41,15 +41,14 @@ impl TypeCheck for Predicate {
 mod tests {
     use std::sync::Arc;
 
-    use crate::{compiler::{ast::Node, semantic::TypeCheck}, buffer::tuple::{RowTable, DatumTypes, Table}, storage::folder::Folder, operator::predicate::{Equal, Field, Predicate}};
+    use crate::{compiler::{ast::Node, semantic::TypeCheck}, buffer::tuple::{RowTable, DatumTypes, Table}, storage::folder::test_folder, operator::predicate::{Equal, Field, Predicate}, error::Error};
 
 
     #[test]
     fn test_type_check() {
-        Folder::create().unwrap();
         let a = "a".to_string();
         let b = "b".to_string();
-        let f = Arc::new(Folder::new().unwrap());
+        let f = test_folder("type_check");
         RowTable::create(Arc::clone(&f), &a, vec![("id".into(), DatumTypes::Int)]).unwrap();
         RowTable::create(Arc::clone(&f), &b, vec![("id".into(), DatumTypes::Int)]).unwrap();
         let b = Node { table: b.clone(), cols: vec![], pred: Some(Predicate::Equal(Equal { l: Field { table: a.to_string(), col: "id".into() }, r: Field { table: b.to_string(), col: "id".into() }})), join: None};
@@ -57,5 +56,21 @@ mod tests {
         a.check(Arc::clone(&f)).unwrap();
         assert!(a.check(Arc::clone(&f)).is_ok());
     }
-}
 
+    #[test]
+    fn test_join_compatibility() {
+        let f = test_folder("join_compatibility");
+        RowTable::create(Arc::clone(&f), "a", vec![("small".into(), DatumTypes::SmallInt), ("flag".into(), DatumTypes::Boolean)]).unwrap();
+        RowTable::create(Arc::clone(&f), "b", vec![("big".into(), DatumTypes::BigInt), ("price".into(), DatumTypes::Double)]).unwrap();
+        let join = |l: &str, r: &str| {
+            let b = Node { table: "b".into(), cols: vec![], pred: Some(Predicate::Equal(Equal::new(Field::new("a", l), Field::new("b", r)))), join: None };
+            Node { table: "a".into(), cols: vec![], pred: None, join: Some(Box::new(b)) }.check(Arc::clone(&f))
+        };
+        assert!(join("small", "big").is_ok());
+        assert!(matches!(join("small", "price"), Err(Error::TypeMismatch)));
+        assert!(matches!(join("flag", "big"), Err(Error::TypeMismatch)));
+        assert!(!DatumTypes::Float.compatible(&DatumTypes::Double));
+        assert!(DatumTypes::Double.compatible(&DatumTypes::Double));
+        assert!(DatumTypes::Boolean.compatible(&DatumTypes::Boolean));
+    }
+}
@@ -4,7 +4,9 @@ use std::{fmt, io::Error as IoError};
 pub enum PageError {
     NoBlock,
     OutOfBounds,
-    InvalidTuple
+    InvalidTuple,
+    Corrupted { page_id: u128 },
+    ShortRead { page_id: u128 }
 }
 
 #[derive(Debug, )]
@@ -15,7 +17,11 @@ pub enum Error {
     InvalidName,
     ColumnDoesNotExist,
     TableDoesNotExist,
-    TypeMismatch
+    TableAlreadyExists,
+    TypeMismatch,
+    InvalidCatalog,
+    BufferFull,
+    ReadOnly
 }
 
 impl From<IoError> for Error {
@@ -1,23 +1,30 @@
-use std::{sync::Arc, ptr};
+use std::{sync::Arc, any::Any};
 
-use crate::{storage::{utils::{append_block, delete_file}, folder::{Folder, TableInode}, disk_manager::SET_64}, buffer::{tuple::{Tuple, TableIter, Table, Schema, PageBuffer}, Buff}, error::Error};
+use crate::{storage::{utils::{append_block, delete_file}, folder::{Folder, TableInode}, disk_manager::SET_64, database::Database}, buffer::{tuple::{Tuple, TableIter, Table, Schema, PageBuffer}, page::Page, Buff, strategy::Strategy, read_ahead::ReadAhead}, error::{Error, PageError}};
 use serde::{Serialize, Deserialize};
 
 const KEYNO: usize = 1 << 15;
 
+/// The bucket of a 16-bit key; keys are folded onto the `KEYNO` buckets.
+fn bucket(key: u16) -> usize {
+    key as usize % KEYNO
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct HashTable {
     inode: TableInode,
     temp: bool,
     num_blocks: u32,
     pub keys: Vec<Option<u32>>,
-    schema: Schema
+    schema: Schema,
+    #[serde(skip)]
+    db: Option<Arc<Database>>
 }
 
 impl Drop for HashTable {
     fn drop(&mut self) { 
         if self.temp() { 
-            delete_file(&self.inode.data_ino.to_string()).unwrap();
+            delete_file(self.db(), &self.inode.data_ino.to_string()).unwrap();
         }
     }
 }
@@ -47,8 +54,25 @@ impl Table for HashTable {
         self.schema = schema
     }
 
+    fn set_db(&mut self, db: Arc<Database>) {
+        self.db = Some(db)
+    }
+
+    fn reset(&mut self) {
+        self.num_blocks = 0;
+        self.keys = vec![None; KEYNO];
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
     fn create(f: Arc<Folder>, name: &str, schema: Schema) -> Result<Self, Error> {
-        Ok(f.create_table(name, schema)?)
+        f.create_table(name, schema)
     }
 
     fn create_temp(f: Arc<Folder>, schema: Schema) -> Result<Self, Error> {
@@ -56,7 +80,7 @@ impl Table for HashTable {
     }
 
     fn new(f: Arc<Folder>, name: &str) -> Result<Self, Error> {
-        Ok((f.fetch_table(&name)?).ok_or(Error::TableDoesNotExist)?)
+        f.read_table(name)?.ok_or(Error::TableDoesNotExist)
     }
 }
 
@@ -67,15 +91,20 @@ impl Default for HashTable {
             temp: false,
             num_blocks: 0,
             keys: vec![None; KEYNO],
-            schema: vec![]
+            schema: vec![],
+            db: None
         }
     }
 }
 
 impl HashTable {
 
+    fn db(&self) -> &Database {
+        self.db.as_ref().expect("table is not attached to a database")
+    }
+
     pub fn append_block(&mut self) -> Result<(), Error> {
-        append_block(&self.inode.data_ino.to_string()).unwrap();
+        append_block(self.db(), &self.inode.data_ino.to_string())?;
         self.num_blocks += 1;
         Ok(())
     }
@@ -86,62 +115,59 @@ pub trait HashIter {
     fn swap_key(&mut self, key: u16);
 }
 
+/// Follows the bucket to its next block, ending the scan at the last one.
+fn next_in_chain(i: &mut TableIter<HashTable>, page: &Page) -> bool {
+    let Some(next) = page.get_next() else { return true; };
+    i.block_num = Some(next as u64);
+    i.tup_idx = 0;
+    false
+}
+
 impl TableIter<HashTable> {
     pub fn new(buf: Arc<PageBuffer>, table: HashTable) -> Self {
-        TableIter { block_num: None, buf: Arc::clone(&buf), tup_idx: 0, table, page: ptr::null_mut(), on_page_end: |i| {
-            let page = unsafe { i.page.as_ref().unwrap().read().unwrap() };
-            if !page.has_next() { return true;}
-            i.block_num = Some(page.get_next().unwrap() as u64);
-            drop(page);
-            i.tup_idx = 0;
-            false 
-            }
-        }
+        TableIter { block_num: None, read_ahead: ReadAhead::new(buf.read_ahead_window(&Strategy::Normal)), buf: Arc::clone(&buf), tup_idx: 0, table, strategy: Strategy::Normal, on_page_end: next_in_chain, err: None }
     }
 }
 
 impl HashIter for TableIter<HashTable> {
     fn swap_key(&mut self, key: u16) {
-        self.block_num = self.table.keys[key as usize].map(|v| v as u64);
+        self.block_num = self.table.keys[bucket(key)].map(|v| v as u64);
         self.tup_idx = 0;
     }
 }
 
 pub trait Hash {
-    fn read<'a>(self, key: u16, buf: Arc<PageBuffer>) -> TableIter<HashTable>;
-    fn insert(&mut self, key: u16, val: Tuple, buf: Arc<PageBuffer>) -> Result<(), Error>;
+    fn read(self, key: u16, buf: Arc<PageBuffer>) -> TableIter<HashTable>;
+    fn insert(&mut self, key: u16, val: Tuple, buf: Arc<PageBuffer>, strategy: &Strategy) -> Result<(), Error>;
 }
 
 impl Hash for HashTable {
 
-    fn read<'a>(self, key: u16, buf: Arc<PageBuffer>) -> TableIter<HashTable> {
-        let block_num = self.keys[key as usize].map(|v| v as u64);
+    fn read(self, key: u16, buf: Arc<PageBuffer>) -> TableIter<HashTable> {
+        let block_num = self.keys[bucket(key)].map(|v| v as u64);
         let buf = Arc::clone(&buf);
         TableIter { 
             block_num, 
+            read_ahead: ReadAhead::new(buf.read_ahead_window(&Strategy::Normal)),
             buf,
             tup_idx: 0, 
             table: self,  
-            page: ptr::null(),
-            on_page_end: |i| {
-                let page = unsafe { i.page.as_ref().unwrap().read().unwrap() };
-                if !page.has_next() { return true;}
-                i.block_num = Some(page.get_next().unwrap() as u64);
-                drop(page);
-                i.tup_idx = 0;
-                false 
-            } 
+            strategy: Strategy::Normal,
+            on_page_end: next_in_chain,
+            err: None
         }
     }
 
-    fn insert(&mut self, key: u16, val: Tuple, buf: Arc<PageBuffer>) -> Result<(), Error> {
-        if self.keys[key as usize] == None {
-            self.append_block().unwrap();
-            self.keys[key as usize] = Some(self.num_blocks - 1);
-            return self.insert(key, val, Arc::clone(&buf))
+    fn insert(&mut self, key: u16, val: Tuple, buf: Arc<PageBuffer>, strategy: &Strategy) -> Result<(), Error> {
+        // every retry lands on a fresh block, so a row that cannot fit an empty one would never stop
+        if !Page::fits(Page::encode(&val, &self.schema)?.len()) {return Err(Error::PageError(PageError::OutOfBounds));}
+        if self.keys[bucket(key)].is_none() {
+            self.append_block()?;
+            self.keys[bucket(key)] = Some(self.num_blocks - 1);
+            return self.insert(key, val, Arc::clone(&buf), strategy)
         }
-        let block_num = self.keys[key as usize].unwrap();
-        let mut page = buf.fetch((self.inode.data_ino as u128)<<64 | (block_num as u128 & 0xFFFFFFFF));
+        let block_num = self.keys[bucket(key)].unwrap();
+        let mut page = buf.fetch_with((self.inode.data_ino as u128)<<64 | (block_num as u128 & 0xFFFFFFFF), strategy)?;
         let mut next;
         {
             let page_read = page.read().unwrap();
@@ -149,7 +175,7 @@ impl Hash for HashTable {
             drop(page_read);
         }
         while next.is_some() {
-            page = buf.fetch((self.inode.data_ino as u128)<<64 | (next.unwrap() as u128 & SET_64 as u128));
+            page = buf.fetch_with((self.inode.data_ino as u128)<<64 | (next.unwrap() as u128 & SET_64 as u128), strategy)?;
             {
                 let page_read = page.read().unwrap();
                 next = page_read.get_next();
@@ -161,10 +187,10 @@ impl Hash for HashTable {
         match bind {
             Ok(_) => Ok(()),
             Err(_) => {
-                self.append_block().unwrap();
+                self.append_block()?;
                 p.set_next(self.num_blocks - 1);
                 drop(p);
-                self.insert(key, val, Arc::clone(&buf))
+                self.insert(key, val, Arc::clone(&buf), strategy)
             }
         }
     }
@@ -174,21 +200,21 @@ impl Hash for HashTable {
 mod tests {
     use std::sync::Arc;
 
-    use crate::{buffer::tuple::{DatumTypes, Datum, PageBuffer, Table}, storage::folder::Folder};
+    use crate::{buffer::{tuple::{DatumTypes, Datum, PageBuffer, Table}, strategy::Strategy}, storage::folder::test_folder};
 
     use super::{HashTable, Hash};
 
     #[test]
     pub fn test_hash_table() {
         let id = "hash_table".to_string();
-        let f = Arc::new(Folder::new().unwrap());
+        let f = test_folder(&id);
         let mut h = HashTable::create(Arc::clone(&f), &id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
         let key = 10;
         let val = vec![Datum::Int(10), Datum::Int(20)];
-        let val1 = vec![Datum::Int(10), Datum::Int(30)];
-        let buf = Arc::new(PageBuffer::new(10));
-        h.insert(key, val.to_vec(), Arc::clone(&buf)).unwrap();
-        h.insert(key+1, val1.to_vec(), Arc::clone(&buf)).unwrap();
+        let val1 = [Datum::Int(10), Datum::Int(30)];
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        h.insert(key, val.to_vec(), Arc::clone(&buf), &Strategy::Normal).unwrap();
+        h.insert(key+1, val1.to_vec(), Arc::clone(&buf), &Strategy::Normal).unwrap();
         let ret: Vec<Vec<Datum>> = h.read(key, Arc::clone(&buf)).collect();
         assert_eq!(ret, vec![val]);
     }
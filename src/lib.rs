@@ -1,5 +1,4 @@
 #![allow(non_snake_case)]
-#![feature(windows_by_handle)]
 
 use std::sync::{Mutex, Arc};
 
@@ -1,9 +1,9 @@
 #![allow(non_snake_case)]
 
-use std::{io::Result, sync::{Mutex, Arc}};
+use std::{io::Result, sync::{Mutex, Arc}, env, time::Duration};
 
-use actix_web::{get, Responder, HttpResponse, HttpServer, App, web};
-use rustDB::{compiler::parse, buffer::tuple::PageBuffer, State, storage::folder::Folder};
+use actix_web::{get, post, Responder, HttpResponse, HttpServer, App, web};
+use rustDB::{compiler::parse, buffer::{tuple::PageBuffer, writer::Writer, keepers::Policy}, State, storage::{folder::Folder, database::Database, utils::file_exists}};
 
 #[get("/query")]
 async fn query(data: web::Data<State>, query: String) -> impl Responder {
@@ -15,13 +15,42 @@ async fn query(data: web::Data<State>, query: String) -> impl Responder {
     }
 }
 
+/// Frames in use and frames the pool can grow to.
+#[get("/admin/buffer")]
+async fn buffer_size(data: web::Data<State>) -> impl Responder {
+    let buf = Arc::clone(&data.buf.lock().unwrap());
+    HttpResponse::Ok().json((buf.size(), buf.capacity()))
+}
+
+/// Resizes the pool to the number of frames in the body and answers with the size it ended up
+/// with, which is larger than asked while pinned frames are in the way.
+#[post("/admin/buffer")]
+async fn resize_buffer(data: web::Data<State>, size: String) -> impl Responder {
+    let buf = Arc::clone(&data.buf.lock().unwrap());
+    match size.trim().parse().map(|size| buf.resize(size)) {
+        Ok(Ok(size)) => HttpResponse::Ok().json(size),
+        _ => HttpResponse::Ok().body("Failed")
+    }
+}
+
 #[actix_web::main]
 async fn main() -> Result<()> {
-    let state = web::Data::new(State { folder: Mutex::new(Arc::new(Folder::new().unwrap())), buf: Mutex::new(Arc::new(PageBuffer::new(10)))});
+    // `--single` keeps the whole database in one file at the given path
+    let single = env::args().any(|a| a == "--single");
+    let path = env::args().skip(1).find(|a| a != "--single").unwrap_or("data".into());
+    let db = if single { Database::open_single(path) } else { Database::open(path) }.unwrap();
+    if !file_exists(&db, "folder") { Folder::create(Arc::clone(&db)).unwrap(); }
+    // actix workers share the pool, so it is split into shards that do not contend on hits;
+    // it can be grown to 1024 frames through /admin/buffer
+    let buf = Arc::new(PageBuffer::with_capacity(Arc::clone(&db), 10, 1024, 2, Policy::Clock));
+    let _writer = Writer::start(&buf, Duration::from_millis(200), 4);
+    let state = web::Data::new(State { folder: Mutex::new(Arc::new(Folder::new(Arc::clone(&db)).unwrap())), buf: Mutex::new(buf)});
     HttpServer::new(move || {
         App::new()
             .app_data(state.clone())
             .service(query)
+            .service(buffer_size)
+            .service(resize_buffer)
     })
     .bind(("127.0.0.1", 8080))?
     .run()
@@ -1,11 +1,12 @@
 use std::sync::Arc;
 
 use crate::buffer::tuple::{TableIter, Tuple, RowTable, Schema, Operator, Table, PageBuffer};
+use crate::error::Error;
 
 use crate::index::hash_table::{HashTable, Hash, HashIter};
 use crate::storage::folder::Folder;
 
-use self::predicate::Predicate;
+use self::predicate::{Predicate, TupleHash, TupleMatch};
 
 pub mod predicate;
 
@@ -46,6 +47,10 @@ impl Operator for SelectIter {
     fn get_schema(&self) -> Schema {
         self.schema.clone()
     }
+
+    fn take_err(&mut self) -> Option<Error> {
+        self.iter.err.take()
+    }
 }
 
 impl Iterator for SelectIter {
@@ -105,6 +110,10 @@ impl Operator for ProjectIter {
     fn get_schema(&self) -> Schema {
         self.schema.clone()
     }
+
+    fn take_err(&mut self) -> Option<Error> {
+        self.iter.take_err()
+    }
 }
 
 
@@ -131,12 +140,20 @@ pub struct Join {
     pred: Predicate
 }
 
+/// The hash table built over the left input, with what probes it for a right tuple.
+struct Probe {
+    h: TableIter<HashTable>,
+    r_hash: TupleHash,
+    matches: TupleMatch
+}
+
 pub struct JoinIter {
     schema: Schema,
-    h: TableIter<HashTable>,
+    probe: Option<Probe>,
     cur_r: Option<Tuple>,
     r: Box<dyn Operator>,
-    r_hash: Box<dyn Fn(&Tuple) -> u16>
+    /// Why the hash table could not be built, the join yields nothing when it is set.
+    err: Option<Error>
 }
 
 impl Join {
@@ -149,6 +166,18 @@ impl Join {
         schema.append(&mut self.r.get_schema());
         schema
     }
+
+    fn build(&mut self, schema: &Schema) -> Result<Probe, Error> {
+        let mut h = HashTable::create_temp(Arc::clone(&self.f), self.l.get_schema())?;
+        let (l_hash, r_hash) = self.pred.generate_hashes(Arc::clone(&self.f), schema)?;
+        let matches = self.pred.generate_matcher(Arc::clone(&self.f), schema)?;
+        let build = self.buf.build_strategy();
+        for t in self.l.by_ref() {
+            h.insert( l_hash(&t), t, Arc::clone(&self.buf), &build)?;
+        }
+        if let Some(e) = self.l.take_err() { return Err(e); }
+        Ok(Probe { h: TableIter::new(Arc::clone(&self.buf), h), r_hash, matches })
+    }
 }
 
 impl IntoIterator for Join {
@@ -158,12 +187,11 @@ impl IntoIterator for Join {
 
     fn into_iter(mut self) -> Self::IntoIter {
         let schema = self.get_schema();   
-        let mut h = HashTable::create_temp(Arc::clone(&self.f), self.l.get_schema()).unwrap();
-        let (l_hash, r_hash) = self.pred.generate_hashes(Arc::clone(&self.f), &schema).unwrap();
-        while let Some(t) = self.l.next() {
-            h.insert( l_hash(&t), t, Arc::clone(&self.buf)).unwrap();
-        }
-        JoinIter { schema, h: TableIter::new(Arc::clone(&self.buf), h), cur_r: None, r: self.r, r_hash: Box::new(r_hash) }
+        let (probe, err) = match self.build(&schema) {
+            Ok(probe) => (Some(probe), None),
+            Err(e) => (None, Some(e))
+        };
+        JoinIter { schema, probe, cur_r: None, r: self.r, err }
     }
 }
 
@@ -171,6 +199,10 @@ impl Operator for JoinIter {
     fn get_schema(&self) -> Schema {
         self.schema.clone()
     }
+
+    fn take_err(&mut self) -> Option<Error> {
+        self.err.take().or_else(|| self.probe.as_mut().and_then(|p| p.h.err.take())).or_else(|| self.r.take_err())
+    }
 }
 
 impl Iterator for JoinIter {
@@ -178,21 +210,24 @@ impl Iterator for JoinIter {
     type Item = Tuple;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.cur_r == None { 
-            self.cur_r = self.r.next(); 
-            if self.cur_r == None {
-                return None;
-            } else {
-                self.h.swap_key((self.r_hash)(self.cur_r.as_ref().unwrap()));
+        let probe = self.probe.as_mut()?;
+        loop {
+            if self.cur_r.is_none() { 
+                self.cur_r = self.r.next(); 
+                let Some(cur_r) = &self.cur_r else { return None; };
+                probe.h.swap_key((probe.r_hash)(cur_r));
             }
+            let Some(mut cur_l) = probe.h.next() else {
+                if probe.h.err.is_some() { return None; }
+                self.cur_r = None;
+                continue;
+            };
+            let cur_r = self.cur_r.as_ref().unwrap();
+            // the bucket also holds tuples whose keys merely hash alike
+            if !(probe.matches)(&cur_l, cur_r) { continue; }
+            cur_l.extend_from_slice(cur_r);
+            return Some(cur_l);
         }
-        let Some(mut cur_l) = self.h.next() else {
-            self.cur_r = None;
-            return self.next();
-        };
-
-        cur_l.extend_from_slice(&self.cur_r.as_ref().unwrap());
-        Some(cur_l)
     }
 }
 
@@ -201,28 +236,26 @@ mod tests {
 
     use std::sync::Arc;
 
-    use crate::{buffer::tuple::{RowTable, DatumTypes, Tuple, Datum, TupleOps, PageBuffer, Table}, operator::{Project, predicate::{Predicate, Equal, Field}}, storage::folder::Folder};
+    use crate::{buffer::tuple::{RowTable, DatumTypes, Tuple, Datum, TupleOps, PageBuffer, Table, Operator, Hash}, operator::{Project, predicate::{Predicate, Equal, Field}}, storage::folder::test_folder, error::{Error, PageError}};
 
     use super::{Select, Join};
 
     #[test]
     fn test_select() {
         let id = "select";
-        let f = Arc::new(Folder::new().unwrap());
-        let mut t = RowTable::create(f, id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
-        let buf = Arc::new(PageBuffer::new(1));
+        let f = test_folder(id);
+        RowTable::create(Arc::clone(&f), id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 1));
         let mut tuple;
         let mut res: Vec<Tuple> = Vec::new();
         for i in 0..100 {
             tuple = vec![Datum::Int(i), Datum::Int(i+2)];
-            t.add(Arc::clone(&buf), tuple.to_vec()).unwrap();
+            f.update_table(id, |t: &mut RowTable| t.add(Arc::clone(&buf), tuple.to_vec())).unwrap();
             res.push(tuple);
         }
+        let t = RowTable::new(Arc::clone(&f), id).unwrap();
         let s_op = Select::new(t, buf, |t| {
-            match t[0] {
-                Datum::Int(_) => true,
-                _ => false
-            }
+            matches!(t[0], Datum::Int(_))
         }).into_iter();
         assert_eq!(s_op.collect::<Vec<Vec<Datum>>>(), res);
     }
@@ -230,21 +263,19 @@ mod tests {
     #[test]
     fn test_project() {
         let t_id = "test_project".to_string();
-        let f = Arc::new(Folder::new().unwrap());
-        let mut t = RowTable::create(f, &t_id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
-        let buf = Arc::new(PageBuffer::new(1));
+        let f = test_folder(&t_id);
+        RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 1));
         let mut tuple;
         let mut res: Vec<Tuple> = Vec::new();
         for i in 0..100 {
             tuple = vec![Datum::Int(i), Datum::Int(i+2)];
-            t.add(Arc::clone(&buf), tuple.to_vec()).unwrap();
+            f.update_table(&t_id, |t: &mut RowTable| t.add(Arc::clone(&buf), tuple.to_vec())).unwrap();
             res.push(vec![tuple[0].clone()]);
         }
+        let t = RowTable::new(Arc::clone(&f), &t_id).unwrap();
         let s_op = Select::new(t, Arc::clone(&buf), |t| {
-            match t[0] {
-                Datum::Int(_) => true,
-                _ => false
-            }
+            matches!(t[0], Datum::Int(_))
         }).into_iter();
         let proj = Project::new(s_op, buf, vec![t_id + "." + "a"]).into_iter();
         assert_eq!(proj.collect::<Vec<Vec<Datum>>>(), res);
@@ -253,16 +284,18 @@ mod tests {
     #[test]
     fn test_join() {
         let t_id = "test_join".to_string();
-        let f = Arc::new(Folder::new().unwrap());
-        let mut t = RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
-        let mut t2 = RowTable::create(Arc::clone(&f), &(t_id.to_string()+"a"), vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
-        let buf = Arc::new(PageBuffer::new(10));
+        let f = test_folder(&t_id);
+        RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
+        RowTable::create(Arc::clone(&f), &(t_id.to_string()+"a"), vec![("a".into(), DatumTypes::Int), ("b".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
         let mut tuple;
         for i in 0..1 {
             tuple = vec![Datum::Int(i), Datum::Int(i+1)];
-            t.add(Arc::clone(&buf), tuple.to_vec()).unwrap();
-            t2.add(Arc::clone(&buf), tuple.to_vec()).unwrap();
+            f.update_table(&t_id, |t: &mut RowTable| t.add(Arc::clone(&buf), tuple.to_vec())).unwrap();
+            f.update_table(&(t_id.to_string()+"a"), |t: &mut RowTable| t.add(Arc::clone(&buf), tuple.to_vec())).unwrap();
         }
+        let t = RowTable::new(Arc::clone(&f), &t_id).unwrap();
+        let t2 = RowTable::new(Arc::clone(&f), &(t_id.to_string()+"a")).unwrap();
         let s1 = Select::new(t, Arc::clone(&buf), |_| {true}).into_iter();
         let s_op = Join::new( 
             Box::new(s1),
@@ -273,5 +306,102 @@ mod tests {
         ).into_iter();
         assert_eq!(s_op.collect::<Vec<Vec<Datum>>>(), vec![vec![Datum::Int(0),Datum::Int(1),Datum::Int(0),Datum::Int(1)]]);
     }
-}
 
+    #[test]
+    fn test_text_join() {
+        let t_id = "textjoin".to_string();
+        let f = test_folder(&t_id);
+        RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::Varchar(8)), ("b".into(), DatumTypes::Int)]).unwrap();
+        RowTable::create(Arc::clone(&f), &(t_id.to_string()+"a"), vec![("a".into(), DatumTypes::Text)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        for (i, name) in ["ann", "bob", "cid"].into_iter().enumerate() {
+            f.update_table(&t_id, |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Text(name.into()), Datum::Int(i as i32)])).unwrap();
+        }
+        for name in ["cid", "ann", "zed"] {
+            f.update_table(&(t_id.to_string()+"a"), |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Text(name.into())])).unwrap();
+        }
+        let t = RowTable::new(Arc::clone(&f), &t_id).unwrap();
+        let t2 = RowTable::new(Arc::clone(&f), &(t_id.to_string()+"a")).unwrap();
+        let s_op = Join::new(
+            Box::new(Select::new(t, Arc::clone(&buf), |_| true).into_iter()),
+            Box::new(Select::new(t2, Arc::clone(&buf), |_| true).into_iter()),
+            buf,
+            Arc::clone(&f),
+            Predicate::Equal(Equal::new(Field::new(&t_id, "a"), Field::new(&(t_id.clone()+"a"), "a")))
+        ).into_iter();
+        let text = |s: &str| Datum::Text(s.into());
+        assert_eq!(s_op.collect::<Vec<Vec<Datum>>>(), vec![vec![text("cid"), Datum::Int(2), text("cid")], vec![text("ann"), Datum::Int(0), text("ann")]]);
+    }
+
+    #[test]
+    fn test_join_hash_collision() {
+        let t_id = "joincollision".to_string();
+        let f = test_folder(&t_id);
+        RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::Int)]).unwrap();
+        RowTable::create(Arc::clone(&f), &(t_id.to_string()+"a"), vec![("a".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        // both keys fall into bucket 1
+        assert_eq!(Datum::Int(1).hash(), Datum::Int(65536).hash());
+        f.update_table(&t_id, |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(1)])).unwrap();
+        f.update_table(&t_id, |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(65536)])).unwrap();
+        f.update_table(&(t_id.to_string()+"a"), |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(65536)])).unwrap();
+        let t = RowTable::new(Arc::clone(&f), &t_id).unwrap();
+        let t2 = RowTable::new(Arc::clone(&f), &(t_id.to_string()+"a")).unwrap();
+        let s_op = Join::new(
+            Box::new(Select::new(t, Arc::clone(&buf), |_| true).into_iter()),
+            Box::new(Select::new(t2, Arc::clone(&buf), |_| true).into_iter()),
+            buf,
+            Arc::clone(&f),
+            Predicate::Equal(Equal::new(Field::new(&t_id, "a"), Field::new(&(t_id.clone()+"a"), "a")))
+        ).into_iter();
+        assert_eq!(s_op.collect::<Vec<Vec<Datum>>>(), vec![vec![Datum::Int(65536), Datum::Int(65536)]]);
+    }
+
+    #[test]
+    fn test_join_spilled_row() {
+        let t_id = "joinspilled".to_string();
+        let f = test_folder(&t_id);
+        RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::Text)]).unwrap();
+        RowTable::create(Arc::clone(&f), &(t_id.to_string()+"a"), vec![("a".into(), DatumTypes::Text)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        f.update_table(&t_id, |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Text("x".repeat(20000))])).unwrap();
+        f.update_table(&(t_id.to_string()+"a"), |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Text("x".repeat(20000))])).unwrap();
+        let t = RowTable::new(Arc::clone(&f), &t_id).unwrap();
+        let t2 = RowTable::new(Arc::clone(&f), &(t_id.to_string()+"a")).unwrap();
+        let mut s_op = Join::new(
+            Box::new(Select::new(t, Arc::clone(&buf), |_| true).into_iter()),
+            Box::new(Select::new(t2, Arc::clone(&buf), |_| true).into_iter()),
+            buf,
+            Arc::clone(&f),
+            Predicate::Equal(Equal::new(Field::new(&t_id, "a"), Field::new(&(t_id.clone()+"a"), "a")))
+        ).into_iter();
+        // the row is whole again once read, too large for a block of the hash table
+        assert_eq!(s_op.next(), None);
+        assert!(matches!(s_op.take_err(), Some(Error::PageError(PageError::OutOfBounds))));
+    }
+
+    #[test]
+    fn test_join_across_widths() {
+        let t_id = "joinwidths".to_string();
+        let f = test_folder(&t_id);
+        RowTable::create(Arc::clone(&f), &t_id, vec![("a".into(), DatumTypes::SmallInt)]).unwrap();
+        RowTable::create(Arc::clone(&f), &(t_id.to_string()+"a"), vec![("a".into(), DatumTypes::BigInt)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        for i in [-5, 3, 300] {
+            f.update_table(&t_id, |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::SmallInt(i)])).unwrap();
+        }
+        for i in [300, 5_000_000_000, -5] {
+            f.update_table(&(t_id.to_string()+"a"), |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::BigInt(i)])).unwrap();
+        }
+        let t = RowTable::new(Arc::clone(&f), &t_id).unwrap();
+        let t2 = RowTable::new(Arc::clone(&f), &(t_id.to_string()+"a")).unwrap();
+        let s_op = Join::new(
+            Box::new(Select::new(t, Arc::clone(&buf), |_| true).into_iter()),
+            Box::new(Select::new(t2, Arc::clone(&buf), |_| true).into_iter()),
+            buf,
+            Arc::clone(&f),
+            Predicate::Equal(Equal::new(Field::new(&t_id, "a"), Field::new(&(t_id.clone()+"a"), "a")))
+        ).into_iter();
+        assert_eq!(s_op.collect::<Vec<Vec<Datum>>>(), vec![vec![Datum::SmallInt(300), Datum::BigInt(300)], vec![Datum::SmallInt(-5), Datum::BigInt(-5)]]);
+    }
+}
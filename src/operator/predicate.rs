@@ -2,6 +2,11 @@ use std::sync::Arc;
 
 use crate::{buffer::tuple::{DatumTypes, RowTable, Table, Tuple, Hash, Schema}, error::Error, storage::folder::Folder};
 
+/// The hash of the column a join compares, taken from one side's tuple.
+pub type TupleHash = Box<dyn Fn(&Tuple) -> u16>;
+/// Whether a left and a right tuple match.
+pub type TupleMatch = Box<dyn Fn(&Tuple, &Tuple) -> bool>;
+
 
 #[derive(Debug, PartialEq, Clone)]
 pub struct Field {
@@ -31,19 +36,32 @@ impl Equal {
         Self { l, r }
     }
 
-    pub fn generate_hashes(&self, f: Arc<Folder>, schema: &Schema) -> Result<(impl Fn(&Tuple) -> u16, impl Fn(&Tuple) -> u16), Error> {
+    /// Positions of the two columns in the left and right tuples of a join over `schema`.
+    fn columns(&self, f: Arc<Folder>, schema: &Schema) -> Result<(usize, usize), Error> {
         let l_len = RowTable::new(Arc::clone(&f), &self.l.table)?.schema().len();
         let l_idx = schema.iter().enumerate().find(|(_, (col, _))| col == &(self.l.table.clone() + "." + &self.l.col)).map(|(idx, _)| idx).ok_or(Error::ColumnDoesNotExist)?;
         let r_idx = schema.iter().enumerate().find(|(_, (col, _))| col == &(self.r.table.clone() + "." + &self.r.col)).map(|(idx, _)| idx).ok_or(Error::ColumnDoesNotExist)? - l_len;
+        Ok((l_idx, r_idx))
+    }
+
+    pub fn generate_hashes(&self, f: Arc<Folder>, schema: &Schema) -> Result<(TupleHash, TupleHash), Error> {
+        let (l_idx, r_idx) = self.columns(f, schema)?;
         Ok((
-            move |tuple: &Tuple| {
+            Box::new(move |tuple: &Tuple| {
                 tuple[l_idx].hash()
-            },
-            move |tuple: &Tuple| {
+            }),
+            Box::new(move |tuple: &Tuple| {
                 tuple[r_idx].hash()
-            }
+            })
         ))
     }
+
+    /// Whether a left and a right tuple agree on the two columns; tuples in the same hash
+    /// bucket need not.
+    pub fn generate_matcher(&self, f: Arc<Folder>, schema: &Schema) -> Result<TupleMatch, Error> {
+        let (l_idx, r_idx) = self.columns(f, schema)?;
+        Ok(Box::new(move |l: &Tuple, r: &Tuple| l[l_idx].join_eq(&r[r_idx])))
+    }
 }
 
 #[derive(Debug, PartialEq, Clone)]
@@ -52,9 +70,15 @@ pub enum Predicate {
 }
 
 impl Predicate {
-    pub fn generate_hashes(&self, f: Arc<Folder>, schema: &Schema) -> Result<(impl Fn(&Tuple) -> u16, impl Fn(&Tuple) -> u16), Error> {
+    pub fn generate_hashes(&self, f: Arc<Folder>, schema: &Schema) -> Result<(TupleHash, TupleHash), Error> {
         match self {
             Self::Equal(e) => e.generate_hashes(Arc::clone(&f), schema)
         }
     }
-}
\ No newline at end of file
+
+    pub fn generate_matcher(&self, f: Arc<Folder>, schema: &Schema) -> Result<TupleMatch, Error> {
+        match self {
+            Self::Equal(e) => e.generate_matcher(Arc::clone(&f), schema)
+        }
+    }
+}
@@ -0,0 +1,81 @@
+use std::{path::{Path, PathBuf}, fs::create_dir_all, sync::Arc};
+
+use crate::error::Error;
+
+use super::{wal::Wal, file_cache::FileCache, single_file::SingleFile};
+
+const OPEN_FILES: usize = 64;
+
+#[derive(Debug)]
+pub struct Database {
+    root: PathBuf,
+    wal: Wal,
+    files: FileCache,
+    single: Option<SingleFile>
+}
+
+impl PartialEq for Database {
+    fn eq(&self, other: &Self) -> bool {
+        self.root == other.root
+    }
+}
+
+impl Database {
+    pub fn open<P: AsRef<Path>>(root: P) -> Result<Arc<Self>, Error> {
+        create_dir_all(&root)?;
+        let wal = Wal::open(&root.as_ref().join("wal"))?;
+        Ok(Arc::new(Self { root: root.as_ref().to_path_buf(), wal, files: FileCache::new(OPEN_FILES), single: None }))
+    }
+
+    /// Opens a database kept in the single file `path`, creating it if needed.
+    /// The log is kept next to it in `<path>-wal`.
+    pub fn open_single<P: AsRef<Path>>(path: P) -> Result<Arc<Self>, Error> {
+        let path = path.as_ref();
+        let root = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        if !root.as_os_str().is_empty() { create_dir_all(&root)?; }
+        let mut wal_name = path.file_name().unwrap_or_default().to_os_string();
+        wal_name.push("-wal");
+        let wal = Wal::open(&path.with_file_name(wal_name))?;
+        let single = SingleFile::open(path)?;
+        Ok(Arc::new(Self { root, wal, files: FileCache::new(OPEN_FILES), single: Some(single) }))
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    pub fn path(&self, file_name: &str) -> PathBuf {
+        self.root.join(file_name)
+    }
+
+    pub fn wal(&self) -> &Wal {
+        &self.wal
+    }
+
+    pub fn files(&self) -> &FileCache {
+        &self.files
+    }
+
+    pub fn single(&self) -> Option<&SingleFile> {
+        self.single.as_ref()
+    }
+}
+
+#[cfg(test)]
+pub fn test_db(name: &str) -> Arc<Database> {
+    let root = std::env::temp_dir().join("rustDB_tests").join(name);
+    let _ = std::fs::remove_dir_all(&root);
+    Database::open(root).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::test_db;
+
+    #[test]
+    fn test_database_open() {
+        let db = test_db("database_open");
+        assert!(db.root().is_dir());
+        assert_eq!(db.path("folder"), db.root().join("folder"));
+    }
+}
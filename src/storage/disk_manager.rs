@@ -1,25 +1,72 @@
-use std::io::{SeekFrom, Read, Seek, Write};
+use std::{io::ErrorKind, fs::File, sync::Arc};
 
-use super::{BLCKSIZ, utils::{open_file, write_file}, Block};
+use crate::error::{Error, PageError};
+
+use super::{BLCKSIZ, Block, database::Database, file_cache::{read_at, write_at}};
 
 pub const SET_64: u64 = 0xFFFFFFFFFFFFFFFF; 
 
-pub fn write_block(page_id: u128, block: &Block) -> Option<()> {
-    let f_id = (page_id>>64 as u64).to_string();
+/// Handle and byte offset of a block in whichever layout the database uses.
+fn locate(db: &Database, page_id: u128) -> Result<(Arc<File>, u64), Error> {
+    let f_id = (page_id>>64).to_string();
     let b_id = (page_id&SET_64 as u128) as u64;
-    let mut f = write_file(&f_id).expect("Could not write to file");
-    let bytes = bincode::serialize(block).expect("Could not serialize block");
-    f.seek(SeekFrom::Start(b_id * BLCKSIZ as u64)).unwrap();
-    f.write_all(&bytes).expect("Could not read page from file");
-    Some(())
+    match db.single() {
+        Some(s) => {
+            let offset = s.offset(&f_id, b_id)?.ok_or(Error::PageError(PageError::ShortRead { page_id }))?;
+            Ok((s.file(), offset))
+        },
+        None => Ok((db.files().get(db.root(), &f_id)?, b_id * BLCKSIZ as u64))
+    }
 }
 
-pub fn read_block(page_id: u128) -> Block {
-    let f_id = (page_id>>64 as u64).to_string();
-    let b_id = (page_id&SET_64 as u128) as u64;
-    let mut f = open_file(&f_id).expect("File not found");
+pub fn write_block(db: &Database, page_id: u128, block: &Block) -> Result<(), Error> {
+    let (f, offset) = locate(db, page_id)?;
+    let mut block = block.clone();
+    block.checksum = block.compute_checksum();
+    let bytes = bincode::serialize(&block).map_err(|_| Error::PageError(PageError::Corrupted { page_id }))?;
+    write_at(&f, &bytes, offset)?;
+    Ok(())
+}
+
+/// Reads and verifies one block; a block cut short by the end of the file is reported
+/// as `ShortRead` instead of being padded with zeroes.
+pub fn read_block(db: &Database, page_id: u128) -> Result<Block, Error> {
+    let (f, offset) = locate(db, page_id)?;
     let mut block = [0; BLCKSIZ];
-    f.seek(SeekFrom::Start(b_id * BLCKSIZ as u64)).unwrap();
-    f.read(&mut block).expect("Could not read page from file");
-    bincode::deserialize(&block).unwrap()
-}
\ No newline at end of file
+    read_at(&f, &mut block, offset).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => Error::PageError(PageError::ShortRead { page_id }),
+        _ => Error::Io(e)
+    })?;
+    let block: Block = bincode::deserialize(&block).map_err(|_| Error::PageError(PageError::Corrupted { page_id }))?;
+    if block.checksum != block.compute_checksum() { return Err(Error::PageError(PageError::Corrupted { page_id })); }
+    Ok(block)
+}
+
+/// Reads up to `count` blocks from `page_id` on with one read, as many as lie back to back on
+/// disk before the end of the file. Blocks after the first one that fails to verify are left
+/// out; the first block itself is reported like `read_block` does.
+pub fn read_blocks(db: &Database, page_id: u128, count: usize) -> Result<Vec<Block>, Error> {
+    let (f, offset) = locate(db, page_id)?;
+    let n = match db.single() {
+        Some(s) => {
+            let (f_id, b_id) = ((page_id>>64).to_string(), (page_id&SET_64 as u128) as u64);
+            (1..count as u64).take_while(|i| s.offset(&f_id, b_id + i).ok().flatten() == Some(offset + i * BLCKSIZ as u64)).count() + 1
+        },
+        None => (f.metadata()?.len().saturating_sub(offset) / BLCKSIZ as u64).clamp(1, count.max(1) as u64) as usize
+    };
+    let mut bytes = vec![0; n * BLCKSIZ];
+    read_at(&f, &mut bytes, offset).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => Error::PageError(PageError::ShortRead { page_id }),
+        _ => Error::Io(e)
+    })?;
+    let mut blocks = Vec::with_capacity(n);
+    for (i, chunk) in bytes.chunks(BLCKSIZ).enumerate() {
+        let id = page_id + i as u128;
+        match bincode::deserialize::<Block>(chunk) {
+            Ok(block) if block.checksum == block.compute_checksum() => blocks.push(block),
+            _ if i == 0 => return Err(Error::PageError(PageError::Corrupted { page_id: id })),
+            _ => break
+        }
+    }
+    Ok(blocks)
+}
@@ -0,0 +1,153 @@
+use std::{fs::{File, OpenOptions}, path::Path, sync::{Arc, Mutex}, collections::{HashMap, hash_map::DefaultHasher}, hash::{Hash, Hasher}, io};
+
+/// Locks that appends to a file take, picked by its name so they outlive its handle.
+const APPEND_LOCKS: usize = 16;
+
+/// Open handles of the data files, keyed by file name and shared between threads.
+///
+/// Reads and writes are positional, so one handle serves every caller without seeking.
+/// At most `cap` files stay open; the least recently used one is closed first.
+#[derive(Debug)]
+pub struct FileCache {
+    cap: usize,
+    inner: Mutex<CacheInner>,
+    appends: [Mutex<()>; APPEND_LOCKS]
+}
+
+#[derive(Debug, Default)]
+struct CacheInner {
+    tick: u64,
+    files: HashMap<String, (Arc<File>, u64)>
+}
+
+impl FileCache {
+    pub fn new(cap: usize) -> Self {
+        Self { cap: cap.max(1), inner: Mutex::new(CacheInner::default()), appends: std::array::from_fn(|_| Mutex::new(())) }
+    }
+
+    pub fn get(&self, root: &Path, file_name: &str) -> Result<Arc<File>, io::Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.tick += 1;
+        let tick = inner.tick;
+        if let Some((f, used)) = inner.files.get_mut(file_name) {
+            *used = tick;
+            return Ok(Arc::clone(f));
+        }
+        let f = Arc::new(OpenOptions::new().read(true).write(true).open(root.join(file_name))?);
+        if inner.files.len() >= self.cap {
+            let lru = inner.files.iter().min_by_key(|(_, (_, used))| *used).map(|(name, _)| name.clone()).unwrap();
+            inner.files.remove(&lru);
+        }
+        inner.files.insert(file_name.to_owned(), (Arc::clone(&f), tick));
+        Ok(f)
+    }
+
+    /// Writes `buf` at the end of the file. The length is read and written under the file's
+    /// append lock, so two appenders never get the same offset.
+    pub fn append(&self, root: &Path, file_name: &str, buf: &[u8]) -> Result<(), io::Error> {
+        let mut h = DefaultHasher::new();
+        file_name.hash(&mut h);
+        let _append = self.appends[h.finish() as usize % APPEND_LOCKS].lock().unwrap();
+        let f = self.get(root, file_name)?;
+        write_at(&f, buf, f.metadata()?.len())
+    }
+
+    /// Drops the handle of a file that is being deleted or renamed.
+    pub fn close(&self, file_name: &str) {
+        self.inner.lock().unwrap().files.remove(file_name);
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().files.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(unix)]
+pub fn read_at(f: &File, buf: &mut [u8], offset: u64) -> Result<(), io::Error> {
+    std::os::unix::fs::FileExt::read_exact_at(f, buf, offset)
+}
+
+#[cfg(unix)]
+pub fn write_at(f: &File, buf: &[u8], offset: u64) -> Result<(), io::Error> {
+    std::os::unix::fs::FileExt::write_all_at(f, buf, offset)
+}
+
+#[cfg(windows)]
+pub fn read_at(f: &File, mut buf: &mut [u8], mut offset: u64) -> Result<(), io::Error> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match f.seek_read(buf, offset)? {
+            0 => return Err(io::Error::from(io::ErrorKind::UnexpectedEof)),
+            n => { buf = &mut buf[n..]; offset += n as u64; }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+pub fn write_at(f: &File, mut buf: &[u8], mut offset: u64) -> Result<(), io::Error> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        let n = f.seek_write(buf, offset)?;
+        buf = &buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, thread, collections::HashSet};
+
+    use crate::storage::{database::test_db, utils::{create_file, read_file}};
+
+    use super::{FileCache, read_at, write_at};
+
+    #[test]
+    fn test_file_cache_lru() {
+        let db = test_db("file_cache_lru");
+        for name in ["1", "2", "3"] {
+            create_file(&db, name).unwrap();
+        }
+        let cache = FileCache::new(2);
+        let one = cache.get(db.root(), "1").unwrap();
+        cache.get(db.root(), "2").unwrap();
+        assert!(Arc::ptr_eq(&one, &cache.get(db.root(), "1").unwrap()));
+        cache.get(db.root(), "3").unwrap();
+        assert_eq!(cache.len(), 2);
+        // "2" was least recently used and got closed, "1" is still the same handle
+        assert!(Arc::ptr_eq(&one, &cache.get(db.root(), "1").unwrap()));
+
+        write_at(&one, b"abcd", 4).unwrap();
+        let mut buf = [0; 2];
+        read_at(&one, &mut buf, 6).unwrap();
+        assert_eq!(&buf, b"cd");
+        assert!(read_at(&one, &mut [0; 8], 4).is_err());
+        cache.close("1");
+        assert!(cache.get(db.root(), "4").is_err());
+    }
+
+    #[test]
+    fn test_concurrent_append() {
+        let db = test_db("file_cache_append");
+        create_file(&db, "1").unwrap();
+        let cache = Arc::new(FileCache::new(2));
+        let appenders: Vec<_> = (0..8u8).map(|t| {
+            let (cache, root) = (Arc::clone(&cache), db.root().to_path_buf());
+            thread::spawn(move || for i in 0..50u8 { cache.append(&root, "1", &[t, i].repeat(32)).unwrap(); })
+        }).collect();
+        for a in appenders {
+            a.join().unwrap();
+        }
+
+        // every append got a range of its own
+        let bytes = read_file(&db, "1").unwrap();
+        assert_eq!(bytes.len(), 8 * 50 * 64);
+        assert_eq!(bytes.chunks(64).map(|c| (c[0], c[1])).collect::<HashSet<_>>().len(), 8 * 50);
+        assert!(bytes.chunks(64).all(|c| c == [c[0], c[1]].repeat(32)));
+    }
+}
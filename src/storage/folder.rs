@@ -1,19 +1,13 @@
-use std::{io::{Write, Read}, os::windows::prelude::MetadataExt, sync::RwLock};
+use std::{io::ErrorKind, sync::{Mutex, RwLock, Arc, atomic::{AtomicU64, Ordering}}};
 
 use serde::{Serialize, Deserialize, de::DeserializeOwned};
 
-use crate::{error::Error, buffer::{tuple::{Table, Schema}, Buffer, BufferInner, Clock}};
+use crate::{error::Error, buffer::{tuple::{Table, Schema, PageBuffer}, Buffer, BufferInner, Clock, stats::BufferStats}};
 
-use super::utils::{create_file, open_file, rename_file, write_file, delete_file};
+use super::{utils::{create_file, create_new_file, read_file, write_file, delete_file, rename_file, sync_file}, database::Database, wal::{recover, LogBody}};
 
 pub type HeadBuffer = Buffer<RwLock<Option<Box<dyn Table + Send + Sync>>>, BufferInner<RwLock<Option<Box<dyn Table + Send + Sync>>>>, Clock>;
 
-impl Default for HeadBuffer {
-    fn default() -> Self {
-        HeadBuffer::new(10)
-    }
-}
-
 #[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
 pub struct TableInode {
     pub head_ino: u64,
@@ -24,92 +18,359 @@ impl TableInode {
     pub fn new(head_ino: u64, data_ino: u64) -> Self { Self { head_ino, data_ino } }
 }
 
+/// Table headers kept in memory at once.
+const HEADERS: usize = 16;
+
+const CATALOG_MAGIC: &[u8; 4] = b"BDBC";
+const CATALOG_VERSION: u32 = 1;
+
 #[derive(Serialize, Deserialize)]
+struct Catalog {
+    num_tables: u64,
+    next_ino: u64,
+    tables: Vec<(String, TableInode)>
+}
+
+impl Catalog {
+    /// Writes the catalog next to the live one and renames it into place, so a crash
+    /// leaves either the old or the new catalog behind, never a mix of both.
+    fn write(&self, db: &Database) -> Result<(), Error> {
+        let mut bytes = CATALOG_MAGIC.to_vec();
+        bytes.extend_from_slice(&CATALOG_VERSION.to_le_bytes());
+        bytes.extend_from_slice(&bincode::serialize(self).unwrap());
+        write_file(db, "folder.tmp", &bytes)?;
+        rename_file(db, "folder.tmp", "folder")?;
+        Ok(())
+    }
+
+    fn read(db: &Database) -> Result<Self, Error> {
+        let bytes = read_file(db, "folder")?;
+        if bytes.len() < 8 || &bytes[..4] != CATALOG_MAGIC { return Err(Error::InvalidCatalog); }
+        if u32::from_le_bytes(bytes[4..8].try_into().unwrap()) != CATALOG_VERSION { return Err(Error::InvalidCatalog); }
+        bincode::deserialize(&bytes[8..]).map_err(|_| Error::InvalidCatalog)
+    }
+}
+
+/// What a checkpoint made durable: the dirty pages written back and the tables whose files were synced.
+#[derive(Debug, Default, PartialEq)]
+pub struct Checkpoint {
+    pub pages: usize,
+    pub tables: usize
+}
+
 pub struct Folder {
     num_tables: u64,
+    next_ino: AtomicU64,
     tables: RwLock<Vec<(String, TableInode)>>,
-    #[serde(skip)]
-    buf: HeadBuffer
+    /// Held by whoever writes the catalog, before `tables`.
+    catalog: Mutex<()>,
+    db: Arc<Database>,
+    heads: HeadBuffer
 }
 
 impl Folder {
 
-    pub fn create() -> Result<(), Error> {
-        let mut file = create_file("folder")?;
-        let folder = Folder { num_tables: 0, tables: RwLock::new(vec![]), buf: HeadBuffer::default() };
-        file.write_all(&bincode::serialize(&folder).unwrap())?;
-        Ok(())
+    pub fn create(db: Arc<Database>) -> Result<(), Error> {
+        Catalog { num_tables: 0, next_ino: 1, tables: vec![] }.write(&db)
     }
  
-    pub fn new() -> Result<Self, Error> {
-        let mut folder = open_file("folder")?;
-        let mut bytes = Vec::new();
-        folder.read_to_end(&mut bytes).unwrap();
-        Ok(bincode::deserialize(&bytes).unwrap())
+    pub fn new(db: Arc<Database>) -> Result<Self, Error> {
+        recover(&db)?;
+        let catalog = Catalog::read(&db)?;
+        Ok(Folder { num_tables: catalog.num_tables, next_ino: AtomicU64::new(catalog.next_ino), tables: RwLock::new(catalog.tables), catalog: Mutex::new(()), heads: HeadBuffer::new(Arc::clone(&db), HEADERS), db })
     }
 
-    pub fn create_file() -> Result<u64, std::io::Error> {
-        let f = create_file("temp")?;
-        let meta = f.metadata()?;
-        let inode = meta.file_index().unwrap();
-        rename_file("temp", &inode.to_string())?;
-        Ok(inode)
+    pub fn db(&self) -> Arc<Database> {
+        Arc::clone(&self.db)
+    }
+
+    pub fn create_file(&self) -> Result<u64, std::io::Error> {
+        loop {
+            let inode = self.next_ino.fetch_add(1, Ordering::SeqCst);
+            match create_new_file(&self.db, &inode.to_string()) {
+                Ok(_) => return Ok(inode),
+                // ids handed out before a crash may not have made it into the saved catalog
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e)
+            }
+        }
     }
 
     pub fn create_temp_table<T: Table + Default + Serialize>(&self, schema: Schema) -> Result<T, std::io::Error> {
-        let data_ino = Self::create_file()?;
+        let data_ino = self.create_file()?;
         let mut table = T::default();
         let schema = schema.into_iter().map(|t| (data_ino.to_string()+"."+&t.0, t.1)).collect();
         table.set_inode(TableInode::new(0, data_ino));
         table.set_schema(schema);
         table.set_temp(true);
+        table.set_db(self.db());
         Ok(table)
     }
 
-    pub fn create_table<T: Table + Default + Serialize>(&self, name: &str, schema: Schema) -> Result<T, std::io::Error> {
-        let data_ino = Self::create_file()?;
-        let head_ino = Self::create_file()?;
+    /// Creates the table's files and persists the catalog before returning.
+    pub fn create_table<T: Table + Default + Serialize>(&self, name: &str, schema: Schema) -> Result<T, Error> {
+        let data_ino = self.create_file()?;
+        let head_ino = self.create_file()?;
         let mut table = T::default();
         let schema = schema.into_iter().map(|t| (name.to_owned()+"."+&t.0, t.1)).collect();
         table.set_inode(TableInode::new(head_ino, data_ino));
         table.set_schema(schema);
         table.set_temp(false);
-        let mut f = write_file(&head_ino.to_string())?;
-        f.write_all(&bincode::serialize(&table).unwrap())?;
+        table.set_db(self.db());
+        write_file(&self.db, &head_ino.to_string(), &bincode::serialize(&table).unwrap())?;
+        let _catalog = self.catalog.lock().unwrap();
         let mut tables = self.tables.write().unwrap();
+        if tables.iter().any(|(n, _)| n == name) {
+            drop(tables);
+            delete_file(&self.db, &head_ino.to_string())?;
+            delete_file(&self.db, &data_ino.to_string())?;
+            return Err(Error::TableAlreadyExists);
+        }
         tables.push((name.into(), TableInode::new(head_ino, data_ino)));
+        if let Err(e) = self.persist(&tables) {
+            tables.pop();
+            return Err(e);
+        }
+        table.set_read_only();
         Ok(table)
     }
 
-    pub fn fetch_table<T: DeserializeOwned>(&self, name: &str) -> Result<Option<T>, Error> {
+    /// A read-only copy of the table's header, served from the header cache.
+    pub fn fetch_table<T: Table + DeserializeOwned + Clone + Send + Sync + 'static>(&self, name: &str) -> Result<Option<T>, Error> {
+        let head_ino = self.head_ino(name)?;
+        let frame = self.heads.fetch_with(head_ino, || self.load_header::<T>(head_ino))?;
+        let cached = frame.read().unwrap();
+        let mut table = cached.as_ref().and_then(|t| t.as_any().downcast_ref::<T>()).ok_or(Error::TypeMismatch)?.clone();
+        table.set_read_only();
+        Ok(Some(table))
+    }
+
+    /// The table's header read from its file, for tables whose headers are never cached.
+    pub fn read_table<T: Table + DeserializeOwned>(&self, name: &str) -> Result<Option<T>, Error> {
+        let mut table: T = self.read_header(self.head_ino(name)?)?;
+        table.set_db(self.db());
+        Ok(Some(table))
+    }
+
+    /// Runs `f` on the cached header itself. Every writer going through here shares that one
+    /// header, so concurrent inserts never save over each other's block counts or free space.
+    pub fn update_table<T: Table + DeserializeOwned + Send + Sync + 'static, R>(&self, name: &str, f: impl FnOnce(&mut T) -> Result<R, Error>) -> Result<R, Error> {
+        self.with_header(self.head_ino(name)?, f)
+    }
+
+    fn with_header<T: Table + DeserializeOwned + Send + Sync + 'static, R>(&self, head_ino: u64, f: impl FnOnce(&mut T) -> Result<R, Error>) -> Result<R, Error> {
+        let frame = self.heads.fetch_with(head_ino, || self.load_header::<T>(head_ino))?;
+        let mut cached = frame.write().unwrap();
+        f(cached.as_mut().and_then(|t| t.as_any_mut().downcast_mut::<T>()).ok_or(Error::TypeMismatch)?)
+    }
+
+    pub fn header_stats(&self) -> BufferStats {
+        self.heads.stats()
+    }
+
+    fn head_ino(&self, name: &str) -> Result<u64, Error> {
         let tables = self.tables.read().unwrap();
-        let head_ino = tables.iter().find(|(n, _)| n == name).map(|(_, inode)| inode.head_ino.clone()).ok_or(Error::TableDoesNotExist)?;
+        tables.iter().find(|(n, _)| n == name).map(|(_, inode)| inode.head_ino).ok_or(Error::TableDoesNotExist)
+    }
+
+    fn load_header<T: Table + DeserializeOwned + Send + Sync + 'static>(&self, head_ino: u64) -> Result<Box<dyn Table + Send + Sync>, Error> {
+        let mut table: T = self.read_header(head_ino)?;
+        table.set_db(self.db());
+        Ok(Box::new(table))
+    }
+
+    fn read_header<T: DeserializeOwned>(&self, head_ino: u64) -> Result<T, Error> {
+        let bytes = read_file(&self.db, &head_ino.to_string())?;
+        bincode::deserialize(&bytes).map_err(|_| Error::InvalidCatalog)
+    }
+
+    /// Removes the table from the catalog, discards its buffered pages and deletes its files.
+    pub fn drop_table(&self, name: &str, buf: &PageBuffer) -> Result<(), Error> {
+        let catalog = self.catalog.lock().unwrap();
+        let mut tables = self.tables.write().unwrap();
+        let idx = tables.iter().position(|(n, _)| n == name).ok_or(Error::TableDoesNotExist)?;
+        let (name, inode) = tables.remove(idx);
+        if let Err(e) = self.persist(&tables) {
+            tables.insert(idx, (name, inode));
+            return Err(e);
+        }
         drop(tables);
-        let mut f = open_file(&head_ino.to_string())?;
-        let mut bytes = Vec::new();
-        f.read_to_end(&mut bytes)?;
-        Ok(Some(bincode::deserialize(&bytes).unwrap()))
+        drop(catalog);
+        self.heads.invalidate(inode.head_ino);
+        buf.invalidate(inode.data_ino);
+        delete_file(&self.db, &inode.head_ino.to_string())?;
+        delete_file(&self.db, &inode.data_ino.to_string())?;
+        Ok(())
     }
 
-    pub fn delete_temp_table(inode: TableInode) -> Result<(), Error> {
-        delete_file(&inode.head_ino.to_string())?;
-        delete_file(&inode.data_ino.to_string())?;
+    /// Empties the table's data file and header. The truncation is logged first so that
+    /// recovery never replays older changes into the emptied file.
+    pub fn truncate_table<T: Table + DeserializeOwned + Serialize + Send + Sync + 'static>(&self, name: &str, buf: &PageBuffer) -> Result<(), Error> {
+        self.update_table(name, |table: &mut T| {
+            let inode = table.inode();
+            let txn = self.db.wal().begin();
+            self.db.wal().append(txn, LogBody::Truncate { head_ino: inode.head_ino, data_ino: inode.data_ino });
+            self.db.wal().commit(txn)?;
+            buf.invalidate(inode.data_ino);
+            create_file(&self.db, &inode.data_ino.to_string())?;
+            table.reset();
+            write_file(&self.db, &inode.head_ino.to_string(), &bincode::serialize(&table).unwrap())?;
+            Ok(())
+        })
+    }
+
+    /// Renames the table and rewrites the `name.col` prefixes of its columns.
+    pub fn rename_table<T: Table + DeserializeOwned + Serialize + Send + Sync + 'static>(&self, name: &str, new_name: &str) -> Result<(), Error> {
+        let _catalog = self.catalog.lock().unwrap();
+        let mut tables = self.tables.write().unwrap();
+        if tables.iter().any(|(n, _)| n == new_name) { return Err(Error::TableAlreadyExists); }
+        let idx = tables.iter().position(|(n, _)| n == name).ok_or(Error::TableDoesNotExist)?;
+        let head_ino = tables[idx].1.head_ino;
+        let prefix = name.to_owned() + ".";
+        let rename = |col: String| match col.strip_prefix(&prefix) {
+            Some(col) => new_name.to_owned() + "." + col,
+            None => col
+        };
+        self.with_header(head_ino, |table: &mut T| {
+            let old_schema = table.schema();
+            table.set_schema(old_schema.iter().cloned().map(|(col, ty)| (rename(col), ty)).collect());
+            if let Err(e) = write_file(&self.db, &head_ino.to_string(), &bincode::serialize(&table).unwrap()) {
+                table.set_schema(old_schema);
+                return Err(e.into());
+            }
+            Ok(())
+        })?;
+        tables[idx].0 = new_name.into();
+        if let Err(e) = self.persist(&tables) {
+            tables[idx].0 = name.into();
+            return Err(e);
+        }
         Ok(())
     }
 
-    pub fn save(&self) -> Result<(), Error> {
-        let mut file = create_file("folder")?;
-        file.write_all(&bincode::serialize(self).unwrap())?;
+    pub fn delete_temp_table(&self, inode: TableInode) -> Result<(), Error> {
+        delete_file(&self.db, &inode.head_ino.to_string())?;
+        delete_file(&self.db, &inode.data_ino.to_string())?;
         Ok(())
     }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let _catalog = self.catalog.lock().unwrap();
+        let tables = self.tables.read().unwrap();
+        self.persist(&tables)
+    }
+
+    /// Writes back every dirty page, syncs the data and header files of every table and rewrites
+    /// the catalog. DDL waits until it is done. The log is then truncated up to where it stood
+    /// when the checkpoint began, except for the records of transactions still in flight.
+    pub fn checkpoint<T: Table + DeserializeOwned + Send + Sync + 'static>(&self, buf: &PageBuffer) -> Result<Checkpoint, Error> {
+        let _catalog = self.catalog.lock().unwrap();
+        let tables = self.tables.read().unwrap();
+        let upto = self.db.wal().next_lsn();
+        let pages = buf.write_dirty()?;
+        for (_, inode) in tables.iter() {
+            // a writer extends the data file under the header, so a block whose append was
+            // logged before `upto` is in the file by the time it is synced
+            self.with_header(inode.head_ino, |_: &mut T| {
+                sync_file(&self.db, &inode.data_ino.to_string())?;
+                sync_file(&self.db, &inode.head_ino.to_string())?;
+                Ok(())
+            })?;
+        }
+        self.persist(&tables)?;
+        self.db.wal().truncate(upto)?;
+        Ok(Checkpoint { pages, tables: tables.len() })
+    }
+
+    /// Callers hold `catalog`, which keeps two writers off the temp file.
+    fn persist(&self, tables: &[(String, TableInode)]) -> Result<(), Error> {
+        Catalog { num_tables: self.num_tables, next_ino: self.next_ino.load(Ordering::SeqCst), tables: tables.to_vec() }.write(&self.db)
+    }
+}
+
+#[cfg(test)]
+pub fn test_folder(name: &str) -> Arc<Folder> {
+    let db = super::database::test_db(name);
+    Folder::create(Arc::clone(&db)).unwrap();
+    Arc::new(Folder::new(db).unwrap())
 }
 
 #[cfg(test)]
 mod tests {
-    use super::Folder;
+    use std::{sync::Arc, thread};
+
+    use crate::{storage::{database::test_db, utils::create_file}, buffer::tuple::{RowTable, DatumTypes, Datum, Table, TupleOps, PageBuffer}, error::Error};
+
+    use super::{Folder, test_folder};
 
     #[test]
     pub fn test_folder_create() {
-        Folder::create().unwrap();
+        let db = test_db("folder_create");
+        Folder::create(Arc::clone(&db)).unwrap();
+        Folder::new(db).unwrap();
+    }
+
+    #[test]
+    pub fn test_create_file() {
+        let db = test_db("folder_create_file");
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Folder::new(Arc::clone(&db)).unwrap();
+        assert_eq!(f.create_file().unwrap(), 1);
+        create_file(&db, "2").unwrap();
+        assert_eq!(f.create_file().unwrap(), 3);
+        f.save().unwrap();
+        let f = Folder::new(db).unwrap();
+        assert_eq!(f.create_file().unwrap(), 4);
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn test_catalog_persisted() {
+        let db = test_db("folder_catalog_persisted");
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Folder::new(Arc::clone(&db)).unwrap();
+        let t: RowTable = f.create_table("t", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        drop(f);
+
+        let f = Folder::new(Arc::clone(&db)).unwrap();
+        assert_eq!(f.fetch_table::<RowTable>("t").unwrap().unwrap().inode(), t.inode());
+        assert!(!db.path("folder.tmp").exists());
+
+        let mut bytes = std::fs::read(db.path("folder")).unwrap();
+        bytes[4] += 1;
+        std::fs::write(db.path("folder"), bytes).unwrap();
+        assert!(matches!(Folder::new(db), Err(Error::InvalidCatalog)));
+    }
+
+    #[test]
+    pub fn test_header_cache() {
+        let f = test_folder("folder_header_cache");
+        let buf = Arc::new(PageBuffer::new(f.db(), 10));
+        let mut t: RowTable = f.create_table("t", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        RowTable::new(Arc::clone(&f), "t").unwrap();
+        RowTable::new(Arc::clone(&f), "t").unwrap();
+        let stats = f.header_stats();
+        assert_eq!((stats.hits, stats.misses), (1, 1));
+
+        // copies cannot save over the cached header, writers change it in place
+        assert!(matches!(t.add(Arc::clone(&buf), vec![Datum::Int(1)]), Err(Error::ReadOnly)));
+        assert!(matches!(RowTable::new(Arc::clone(&f), "t").unwrap().vacuum(Arc::clone(&buf)), Err(Error::ReadOnly)));
+        f.update_table("t", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(1)])).unwrap();
+        assert_eq!(RowTable::new(Arc::clone(&f), "t").unwrap().num_blocks, 1);
+
+        let threads: Vec<_> = (0..4).map(|i| {
+            let (f, buf) = (Arc::clone(&f), Arc::clone(&buf));
+            thread::spawn(move || for j in 0..400 {
+                f.update_table("t", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(i * 1000 + j)])).unwrap();
+            })
+        }).collect();
+        threads.into_iter().for_each(|t| t.join().unwrap());
+        let t = RowTable::new(Arc::clone(&f), "t").unwrap();
+        assert!(t.num_blocks > 1);
+        assert_eq!(t.iter(Arc::clone(&buf)).count(), 1601);
+        assert_eq!(f.read_header::<RowTable>(t.inode().head_ino).unwrap().num_blocks, t.num_blocks);
+
+        f.drop_table("t", &buf).unwrap();
+        f.create_table::<RowTable>("t", vec![("b".into(), DatumTypes::Int)]).unwrap();
+        assert_eq!(RowTable::new(Arc::clone(&f), "t").unwrap().num_blocks, 0);
+    }
+}
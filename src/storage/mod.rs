@@ -4,9 +4,8 @@ use serde::{Serialize, Deserialize};
 use serde_with::{serde_as, Bytes};
 
 pub const BLCKSIZ: usize = 8 * 1024;
-pub const DATSIZ: usize = 8171;
-pub const LOCSIZ: u16 = 2;
-pub const BASE_PATH: &str = "C:/Users/vikra/rustDB/cache";
+pub const DATSIZ: usize = 8159;
+pub const LOCSIZ: u16 = 4;
 
 #[derive(Debug, Clone, Copy)]
 pub enum Flags {
@@ -17,6 +16,10 @@ pub enum Flags {
 pub mod disk_manager;
 pub mod utils;
 pub mod folder;
+pub mod database;
+pub mod wal;
+pub mod file_cache;
+pub mod single_file;
 
 
 #[serde_as]
@@ -24,6 +27,8 @@ pub mod folder;
 pub struct Block {
     pub block_id: u32,
     pub next: u32,
+    pub lsn: u64,
+    pub checksum: u32,
     pub flags: u8,
     pub lower: u16, 
     pub upper: u16,
@@ -33,7 +38,21 @@ pub struct Block {
 
 impl Block {
     pub fn new(id: u32) -> Self {
-        Block { block_id: id, next: 0, flags: 0, lower: 0, upper: DATSIZ as u16, data: [0; DATSIZ] }
+        Block { block_id: id, next: 0, lsn: 0, checksum: 0, flags: 0, lower: 0, upper: DATSIZ as u16, data: [0; DATSIZ] }
+    }
+
+    /// CRC-32 of every header field except the checksum itself, followed by the data.
+    pub fn compute_checksum(&self) -> u32 {
+        let crc = [
+            &self.block_id.to_le_bytes()[..],
+            &self.next.to_le_bytes(),
+            &self.lsn.to_le_bytes(),
+            &[self.flags],
+            &self.lower.to_le_bytes(),
+            &self.upper.to_le_bytes(),
+            &self.data
+        ].iter().fold(!0u32, |crc, bytes| crc32_update(crc, bytes));
+        !crc
     }
 
     #[inline]
@@ -63,11 +82,21 @@ impl Block {
     }
 }
 
+fn crc32_update(mut crc: u32, bytes: &[u8]) -> u32 {
+    for b in bytes {
+        crc ^= *b as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xEDB88320 } else { crc >> 1 };
+        }
+    }
+    crc
+}
+
 #[cfg(test)]
 mod tests {
     use crate::storage::BLCKSIZ;
 
-    use super::Block;
+    use super::{Block, crc32_update};
 
     #[test]
     fn test_block_size() {
@@ -75,4 +104,15 @@ mod tests {
         let size = bincode::serialize(&block).unwrap().len();
         assert_eq!(size, BLCKSIZ)
     } 
+
+    #[test]
+    fn test_checksum() {
+        assert_eq!(!crc32_update(!0, b"123456789"), 0xCBF43926);
+        let mut block = Block::new(0);
+        let checksum = block.compute_checksum();
+        block.checksum = checksum;
+        assert_eq!(block.compute_checksum(), checksum);
+        block.data[100] ^= 1;
+        assert_ne!(block.compute_checksum(), checksum);
+    }
 }
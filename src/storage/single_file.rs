@@ -0,0 +1,397 @@
+use std::{fs::{File, OpenOptions}, path::Path, sync::{Arc, Mutex}, collections::{HashMap, HashSet}, io::{self, ErrorKind}, mem};
+
+use serde::{Serialize, Deserialize};
+
+use super::{BLCKSIZ, file_cache::{read_at, write_at}};
+
+const MAGIC: &[u8; 4] = b"BDBF";
+const VERSION: u32 = 1;
+/// Bytes at the start of a directory page holding the number of the next one.
+const LINK: usize = 8;
+
+/// Pages of one file stored inside the database file; `len` is its size in bytes.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+struct Entry {
+    pages: Vec<u64>,
+    len: u64
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Directory {
+    files: HashMap<String, Entry>,
+    free: Vec<u64>
+}
+
+#[derive(Debug)]
+struct State {
+    dir: Directory,
+    /// Pages holding the committed directory.
+    dir_pages: Vec<u64>,
+    /// Pages released since the last commit. The committed directory may still point at
+    /// them, so they are only handed out again once a new directory is in place.
+    pending: Vec<u64>,
+    /// Pages allocated since the last commit, which no committed directory points at.
+    fresh: HashSet<u64>,
+    /// Whether the directory changed since the last commit.
+    dirty: bool,
+    num_pages: u64
+}
+
+/// Every table, index, temp relation and the catalog kept as page ranges of one file.
+///
+/// Page 0 is a superblock pointing at the page directory, which maps file names to their
+/// pages and lists the free ones. The directory is written copy-on-write and the superblock
+/// switched over afterwards, so a crash leaves the previous directory intact.
+///
+/// Creating, deleting, renaming and writing a file commit the directory at once. Appended blocks
+/// and overwritten headers wait for the next `sync`, the write-ahead log replays them after a crash.
+#[derive(Debug)]
+pub struct SingleFile {
+    file: Arc<File>,
+    state: Mutex<State>
+}
+
+impl SingleFile {
+    pub fn open(path: &Path) -> Result<Self, io::Error> {
+        let file = Arc::new(OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?);
+        let single = Self { file, state: Mutex::new(State { dir: Directory::default(), dir_pages: vec![], pending: vec![], fresh: HashSet::new(), dirty: false, num_pages: 1 }) };
+        if single.file.metadata()?.len() == 0 {
+            single.write_superblock(0, 0, 1)?;
+            single.file.sync_all()?;
+            return Ok(single);
+        }
+
+        let mut sb = [0; 32];
+        read_at(&single.file, &mut sb, 0)?;
+        if &sb[..4] != MAGIC || u32::from_le_bytes(sb[4..8].try_into().unwrap()) != VERSION {
+            return Err(io::Error::new(ErrorKind::InvalidData, "not a database file"));
+        }
+        let mut page = u64::from_le_bytes(sb[8..16].try_into().unwrap());
+        let len = u64::from_le_bytes(sb[16..24].try_into().unwrap()) as usize;
+        let num_pages = u64::from_le_bytes(sb[24..32].try_into().unwrap());
+
+        let mut bytes = Vec::with_capacity(len);
+        let mut dir_pages = vec![];
+        while page != 0 && bytes.len() < len {
+            let mut buf = [0; BLCKSIZ];
+            read_at(&single.file, &mut buf, page * BLCKSIZ as u64)?;
+            dir_pages.push(page);
+            let take = (len - bytes.len()).min(BLCKSIZ - LINK);
+            bytes.extend_from_slice(&buf[LINK..LINK + take]);
+            page = u64::from_le_bytes(buf[..LINK].try_into().unwrap());
+        }
+        let dir = if len == 0 { Directory::default() } else {
+            bincode::deserialize(&bytes).map_err(|_| io::Error::new(ErrorKind::InvalidData, "corrupted page directory"))?
+        };
+        *single.state.lock().unwrap() = State { dir, dir_pages, pending: vec![], fresh: HashSet::new(), dirty: false, num_pages };
+        Ok(single)
+    }
+
+    pub fn file(&self) -> Arc<File> {
+        Arc::clone(&self.file)
+    }
+
+    fn write_superblock(&self, dir_head: u64, dir_len: u64, num_pages: u64) -> Result<(), io::Error> {
+        let mut sb = Vec::with_capacity(32);
+        sb.extend_from_slice(MAGIC);
+        sb.extend_from_slice(&VERSION.to_le_bytes());
+        sb.extend_from_slice(&dir_head.to_le_bytes());
+        sb.extend_from_slice(&dir_len.to_le_bytes());
+        sb.extend_from_slice(&num_pages.to_le_bytes());
+        write_at(&self.file, &sb, 0)
+    }
+
+    fn alloc(state: &mut State) -> u64 {
+        let page = state.dir.free.pop().unwrap_or_else(|| {
+            state.num_pages += 1;
+            state.num_pages - 1
+        });
+        state.fresh.insert(page);
+        page
+    }
+
+    /// Frees the pages of a replaced file; those the committed directory uses wait for the next commit.
+    fn release(state: &mut State, pages: Vec<u64>) {
+        for page in pages {
+            if state.fresh.remove(&page) { state.dir.free.push(page); } else { state.pending.push(page); }
+        }
+    }
+
+    /// Writes the directory to pages the committed one does not use, then points the superblock at it.
+    fn commit(&self, state: &mut State) -> Result<(), io::Error> {
+        let mut files = mem::take(&mut state.dir.files);
+        let mut pages = vec![];
+        let (dir, bytes) = loop {
+            // every allocated page shrinks the free list, so serialize until the pages suffice
+            let free = state.dir.free.iter().chain(&state.pending).chain(&state.dir_pages).copied().collect();
+            let dir = Directory { files, free };
+            let bytes = bincode::serialize(&dir).unwrap();
+            if pages.len() * (BLCKSIZ - LINK) >= bytes.len() { break (dir, bytes); }
+            files = dir.files;
+            pages.push(Self::alloc(state));
+        };
+        if let Err(e) = self.write_directory(&bytes, &pages, state.num_pages) {
+            state.dir.files = dir.files;
+            state.dir.free.extend(pages);
+            return Err(e);
+        }
+        state.dir = dir;
+        state.dir_pages = pages;
+        state.pending.clear();
+        state.fresh.clear();
+        state.dirty = false;
+        Ok(())
+    }
+
+    fn write_directory(&self, bytes: &[u8], pages: &[u64], num_pages: u64) -> Result<(), io::Error> {
+        let mut chunks = bytes.chunks(BLCKSIZ - LINK);
+        for (i, page) in pages.iter().enumerate() {
+            let next = pages.get(i + 1).copied().unwrap_or(0);
+            let mut buf = [0; BLCKSIZ];
+            buf[..LINK].copy_from_slice(&next.to_le_bytes());
+            let chunk = chunks.next().unwrap_or(&[]);
+            buf[LINK..LINK + chunk.len()].copy_from_slice(chunk);
+            write_at(&self.file, &buf, page * BLCKSIZ as u64)?;
+        }
+        self.file.sync_data()?;
+        self.write_superblock(pages.first().copied().unwrap_or(0), bytes.len() as u64, num_pages)?;
+        self.file.sync_data()
+    }
+
+    pub fn exists(&self, name: &str) -> bool {
+        self.state.lock().unwrap().dir.files.contains_key(name)
+    }
+
+    /// Creates an empty file, truncating an existing one unless `exclusive` is set.
+    pub fn create(&self, name: &str, exclusive: bool) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if let Some(old) = state.dir.files.insert(name.to_owned(), Entry::default()) {
+            if exclusive {
+                state.dir.files.insert(name.to_owned(), old);
+                return Err(io::Error::from(ErrorKind::AlreadyExists));
+            }
+            Self::release(&mut state, old.pages);
+        }
+        self.commit(&mut state)
+    }
+
+    pub fn delete(&self, name: &str) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        let old = state.dir.files.remove(name).ok_or(io::Error::from(ErrorKind::NotFound))?;
+        Self::release(&mut state, old.pages);
+        self.commit(&mut state)
+    }
+
+    pub fn rename(&self, from: &str, to: &str) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.dir.files.remove(from).ok_or(io::Error::from(ErrorKind::NotFound))?;
+        if let Some(old) = state.dir.files.insert(to.to_owned(), entry) {
+            Self::release(&mut state, old.pages);
+        }
+        self.commit(&mut state)
+    }
+
+    pub fn read(&self, name: &str) -> Result<Vec<u8>, io::Error> {
+        let entry = self.state.lock().unwrap().dir.files.get(name).cloned().ok_or(io::Error::from(ErrorKind::NotFound))?;
+        let mut bytes = vec![0; entry.pages.len() * BLCKSIZ];
+        for (page, buf) in entry.pages.iter().zip(bytes.chunks_mut(BLCKSIZ)) {
+            read_at(&self.file, buf, page * BLCKSIZ as u64)?;
+        }
+        bytes.truncate(entry.len as usize);
+        Ok(bytes)
+    }
+
+    /// Replaces the contents of a file, writing them to fresh pages before the directory switches over.
+    pub fn write(&self, name: &str, bytes: &[u8]) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        let entry = self.write_pages(&mut state, bytes)?;
+        if let Some(old) = state.dir.files.insert(name.to_owned(), entry) {
+            Self::release(&mut state, old.pages);
+        }
+        self.commit(&mut state)
+    }
+
+    /// Replaces the contents of an existing file like `write` without committing the directory.
+    /// Pages written since the last commit are overwritten in place, so saving a header over and
+    /// over does not grow the file.
+    pub fn overwrite(&self, name: &str, bytes: &[u8]) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        let old = state.dir.files.get(name).ok_or(io::Error::from(ErrorKind::NotFound))?;
+        if old.pages.len() == bytes.len().div_ceil(BLCKSIZ) && old.pages.iter().all(|page| state.fresh.contains(page)) {
+            for (page, chunk) in old.pages.iter().zip(bytes.chunks(BLCKSIZ)) {
+                write_at(&self.file, chunk, page * BLCKSIZ as u64)?;
+            }
+            state.dir.files.get_mut(name).unwrap().len = bytes.len() as u64;
+        } else {
+            let entry = self.write_pages(&mut state, bytes)?;
+            let old = state.dir.files.insert(name.to_owned(), entry).unwrap();
+            Self::release(&mut state, old.pages);
+        }
+        state.dirty = true;
+        Ok(())
+    }
+
+    fn write_pages(&self, state: &mut State, bytes: &[u8]) -> Result<Entry, io::Error> {
+        let mut entry = Entry { pages: vec![], len: bytes.len() as u64 };
+        for chunk in bytes.chunks(BLCKSIZ) {
+            let page = Self::alloc(state);
+            let mut buf = [0; BLCKSIZ];
+            buf[..chunk.len()].copy_from_slice(chunk);
+            if let Err(e) = write_at(&self.file, &buf, page * BLCKSIZ as u64) {
+                state.dir.free.push(page);
+                state.dir.free.extend(entry.pages);
+                return Err(e);
+            }
+            entry.pages.push(page);
+        }
+        Ok(entry)
+    }
+
+    pub fn append_block(&self, name: &str, block: &[u8]) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if !state.dir.files.contains_key(name) { return Err(io::Error::from(ErrorKind::NotFound)); }
+        let page = Self::alloc(&mut state);
+        if let Err(e) = write_at(&self.file, block, page * BLCKSIZ as u64) {
+            state.dir.free.push(page);
+            return Err(e);
+        }
+        let entry = state.dir.files.get_mut(name).unwrap();
+        entry.pages.push(page);
+        entry.len += BLCKSIZ as u64;
+        state.dirty = true;
+        Ok(())
+    }
+
+    pub fn blocks(&self, name: &str) -> Result<u64, io::Error> {
+        let state = self.state.lock().unwrap();
+        state.dir.files.get(name).map(|e| e.pages.len() as u64).ok_or(io::Error::from(ErrorKind::NotFound))
+    }
+
+    /// Byte offset of block `block_num` of a file, `None` past its end.
+    pub fn offset(&self, name: &str, block_num: u64) -> Result<Option<u64>, io::Error> {
+        let state = self.state.lock().unwrap();
+        let entry = state.dir.files.get(name).ok_or(io::Error::from(ErrorKind::NotFound))?;
+        Ok(entry.pages.get(block_num as usize).map(|page| page * BLCKSIZ as u64))
+    }
+
+    /// Commits the directory if it changed and flushes the file.
+    pub fn sync(&self) -> Result<(), io::Error> {
+        let mut state = self.state.lock().unwrap();
+        if state.dirty { self.commit(&mut state)?; }
+        self.file.sync_all()
+    }
+
+    pub fn free_pages(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.dir.free.len() + state.pending.len()
+    }
+}
+
+impl Drop for SingleFile {
+    fn drop(&mut self) {
+        let mut state = self.state.lock().unwrap();
+        if state.dirty { let _ = self.commit(&mut state); }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{storage::{database::Database, folder::Folder, utils::{create_file, append_block, file_blocks, file_exists}}, buffer::{tuple::{RowTable, DatumTypes, Datum, TupleOps, PageBuffer, Table}, Buff}};
+
+    use crate::storage::BLCKSIZ;
+
+    use super::SingleFile;
+
+    fn test_path(name: &str) -> std::path::PathBuf {
+        let root = std::env::temp_dir().join("rustDB_tests").join(name);
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(&root).unwrap();
+        root.join("db")
+    }
+
+    #[test]
+    fn test_single_file_directory() {
+        let path = test_path("single_file_directory");
+        let s = SingleFile::open(&path).unwrap();
+        s.create("a", true).unwrap();
+        assert!(s.create("a", true).is_err());
+        s.write("a", &[7; 10000]).unwrap();
+        s.create("b", true).unwrap();
+        s.append_block("b", &[1; crate::storage::BLCKSIZ]).unwrap();
+        s.rename("a", "c").unwrap();
+        drop(s);
+
+        let s = SingleFile::open(&path).unwrap();
+        assert!(!s.exists("a"));
+        assert_eq!(s.read("c").unwrap(), vec![7; 10000]);
+        assert_eq!(s.blocks("b").unwrap(), 1);
+        s.delete("c").unwrap();
+        // the two pages of "c" and those of older directories are reused before the file grows
+        let free = s.free_pages();
+        let len = std::fs::metadata(&path).unwrap().len();
+        assert!(free >= 2);
+        s.write("d", &[1; 100]).unwrap();
+        assert_eq!(s.free_pages(), free - 1);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+    }
+
+    #[test]
+    fn test_single_file_batched_commit() {
+        let path = test_path("single_file_batched_commit");
+        let s = SingleFile::open(&path).unwrap();
+        s.create("b", true).unwrap();
+        s.create("h", true).unwrap();
+        s.append_block("b", &[1; BLCKSIZ]).unwrap();
+        s.overwrite("h", &[0; 100]).unwrap();
+        let len = std::fs::metadata(&path).unwrap().len();
+        for i in 1..100 {
+            s.append_block("b", &[1; BLCKSIZ]).unwrap();
+            s.overwrite("h", &[i; 100]).unwrap();
+        }
+        // no directory was written, and the header kept the page it got after the last commit
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len + 99 * BLCKSIZ as u64);
+        // a crash before the next sync leaves the directory as it was committed
+        std::mem::forget(s);
+        let s = SingleFile::open(&path).unwrap();
+        assert_eq!(s.blocks("b").unwrap(), 0);
+        assert_eq!(s.read("h").unwrap(), Vec::<u8>::new());
+
+        s.append_block("b", &[1; BLCKSIZ]).unwrap();
+        s.overwrite("h", &[7; 100]).unwrap();
+        s.sync().unwrap();
+        std::mem::forget(s);
+        let s = SingleFile::open(&path).unwrap();
+        assert_eq!(s.blocks("b").unwrap(), 1);
+        assert_eq!(s.read("h").unwrap(), vec![7; 100]);
+    }
+
+    #[test]
+    fn test_single_file_table() {
+        let path = test_path("single_file_table");
+        let db = Database::open_single(&path).unwrap();
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        let t = RowTable::create(Arc::clone(&f), "t", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        for i in 0..2000 {
+            f.update_table("t", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(i)])).unwrap();
+        }
+        buf.flush().unwrap();
+        create_file(&db, "loose").unwrap();
+        append_block(&db, "loose").unwrap();
+        drop((t, buf, f, db));
+
+        assert_eq!(std::fs::read_dir(path.parent().unwrap()).unwrap().count(), 2);
+        let db = Database::open_single(&path).unwrap();
+        assert!(file_exists(&db, "folder"));
+        assert_eq!(file_blocks(&db, "loose").unwrap(), 1);
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        let t = RowTable::new(Arc::clone(&f), "t").unwrap();
+        // large enough to read ahead, across pages that need not be adjacent in the file
+        let buf = Arc::new(PageBuffer::new(db, 64));
+        assert_eq!(t.iter(Arc::clone(&buf)).map(|r| r[0].clone()).collect::<Vec<_>>(), (0..2000).map(Datum::Int).collect::<Vec<_>>());
+        assert!(buf.stats().prefetched > 0);
+    }
+}
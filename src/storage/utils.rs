@@ -1,37 +1,96 @@
-use std::{fs::{OpenOptions, File, remove_file, rename}, io::Write};
+use std::{fs::{OpenOptions, File, remove_file, rename, read}, io::{Write, ErrorKind}};
 
-use super::{BASE_PATH, Block, DATSIZ};
+use crate::error::Error;
 
-pub fn create_file(file_name: &str) -> Result<File, std::io::Error> {
-    File::create(BASE_PATH.to_owned()+"/"+file_name)
+use super::{database::Database, Block, DATSIZ, BLCKSIZ, file_cache::write_at};
+
+// Every helper works on both layouts: loose files under the database root, or named
+// page ranges inside the single database file.
+
+pub fn create_file(db: &Database, file_name: &str) -> Result<(), std::io::Error> {
+    if let Some(s) = db.single() { return s.create(file_name, false); }
+    File::create(db.path(file_name)).map(|_| ())
+}
+
+pub fn create_new_file(db: &Database, file_name: &str) -> Result<(), std::io::Error> {
+    if let Some(s) = db.single() { return s.create(file_name, true); }
+    OpenOptions::new().write(true).create_new(true).open(db.path(file_name)).map(|_| ())
 }
 
-pub fn rename_file(from: &str, to: &str) -> Result<(), std::io::Error> {
-    rename(BASE_PATH.to_owned()+"/"+from, BASE_PATH.to_owned()+"/"+to)
+pub fn file_exists(db: &Database, file_name: &str) -> bool {
+    match db.single() {
+        Some(s) => s.exists(file_name),
+        None => db.path(file_name).exists()
+    }
 }
 
-pub fn open_file(file_name: &str) -> Result<File, std::io::Error> {
-    File::open(BASE_PATH.to_owned()+"/"+file_name)
+/// Renames a file, replacing the target, and makes the rename durable.
+pub fn rename_file(db: &Database, from: &str, to: &str) -> Result<(), std::io::Error> {
+    if let Some(s) = db.single() { return s.rename(from, to); }
+    db.files().close(from);
+    db.files().close(to);
+    rename(db.path(from), db.path(to))?;
+    File::open(db.root())?.sync_all()
 }
 
-pub fn write_file(file_name: &str) -> Result<File, std::io::Error> {
-    OpenOptions::new().write(true).open(BASE_PATH.to_owned()+"/"+file_name)
+pub fn read_file(db: &Database, file_name: &str) -> Result<Vec<u8>, std::io::Error> {
+    match db.single() {
+        Some(s) => s.read(file_name),
+        None => read(db.path(file_name))
+    }
 }
 
-pub fn delete_file(file_name: &str) -> Result<(), std::io::Error> {
-    remove_file(BASE_PATH.to_owned()+"/"+file_name)
+/// Creates or truncates a file and durably writes `bytes` to it.
+pub fn write_file(db: &Database, file_name: &str, bytes: &[u8]) -> Result<(), std::io::Error> {
+    if let Some(s) = db.single() { return s.write(file_name, bytes); }
+    let mut f = File::create(db.path(file_name))?;
+    f.write_all(bytes)?;
+    f.sync_all()
 }
 
-pub fn append_block(file_name: &str) -> Result<(), std::io::Error> {
-    let mut f = OpenOptions::new().append(true).open(BASE_PATH.to_owned() + "/" + file_name).expect("Could not open file to append block");
-    let new_block = Block {
+pub fn delete_file(db: &Database, file_name: &str) -> Result<(), std::io::Error> {
+    if let Some(s) = db.single() { return s.delete(file_name); }
+    db.files().close(file_name);
+    remove_file(db.path(file_name))
+}
+
+/// Replaces the contents of an existing file through its cached handle.
+pub fn overwrite_file(db: &Database, file_name: &str, bytes: &[u8]) -> Result<(), std::io::Error> {
+    if let Some(s) = db.single() { return s.overwrite(file_name, bytes); }
+    let f = db.files().get(db.root(), file_name)?;
+    write_at(&f, bytes, 0)?;
+    f.set_len(bytes.len() as u64)
+}
+
+/// Number of whole blocks in a file.
+pub fn file_blocks(db: &Database, file_name: &str) -> Result<u64, std::io::Error> {
+    match db.single() {
+        Some(s) => s.blocks(file_name),
+        None => Ok(db.files().get(db.root(), file_name)?.metadata()?.len() / BLCKSIZ as u64)
+    }
+}
+
+pub fn sync_file(db: &Database, file_name: &str) -> Result<(), std::io::Error> {
+    match db.single() {
+        Some(s) if s.exists(file_name) => s.sync(),
+        Some(_) => Err(std::io::Error::from(ErrorKind::NotFound)),
+        None => db.files().get(db.root(), file_name)?.sync_all()
+    }
+}
+
+pub fn append_block(db: &Database, file_name: &str) -> Result<(), Error> {
+    let mut new_block = Block {
         block_id: 0,
         next: 0,
+        lsn: 0,
+        checksum: 0,
         flags: 0,
         lower: 0,
         upper: DATSIZ as u16,
         data: [0; DATSIZ]
     };
-    let buf = bincode::serialize(&new_block).expect("Could not serialize block");
-    f.write_all(buf.as_slice())
-}
\ No newline at end of file
+    new_block.checksum = new_block.compute_checksum();
+    let buf = bincode::serialize(&new_block).map_err(|e| Error::Io(std::io::Error::other(e)))?;
+    if let Some(s) = db.single() { return Ok(s.append_block(file_name, &buf)?); }
+    Ok(db.files().append(db.root(), file_name, &buf)?)
+}
@@ -0,0 +1,422 @@
+use std::{fs::{self, File, OpenOptions}, io::{Read, Write, Seek, SeekFrom}, path::{Path, PathBuf}, sync::Mutex, collections::{HashMap, HashSet, hash_map::Entry}};
+
+use serde::{Serialize, Deserialize};
+
+use crate::{error::Error, buffer::{page::{Page, TupleCRUD}, tuple::{RowTable, Table}}};
+
+use super::{database::Database, disk_manager::{read_block, write_block, SET_64}, utils::{read_file, write_file, create_file, append_block, file_blocks, file_exists, sync_file}, Block, Flags, DATSIZ};
+
+const HEADSIZ: u64 = 8;
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub enum LogBody {
+    Insert { page_id: u128, slot: u16, data: Vec<u8> },
+    Update { page_id: u128, slot: u16, before: Vec<u8>, after: Vec<u8> },
+    Delete { page_id: u128, slot: u16, loc: u16 },
+    AppendBlock { head_ino: u64, data_ino: u64, block_num: u64 },
+    Compact { page_id: u128 },
+    Overflow { page_id: u128, next: Option<u32>, data: Vec<u8> },
+    Truncate { head_ino: u64, data_ino: u64 },
+    Commit,
+    Abort
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct LogRecord {
+    pub lsn: u64,
+    pub txn: u64,
+    pub body: LogBody
+}
+
+#[derive(Debug)]
+struct WalInner {
+    file: File,
+    next_lsn: u64,
+    next_txn: u64,
+    flushed_lsn: u64,
+    pending: Vec<u8>,
+    /// Transactions that logged something and did not commit or abort yet, with their first lsn.
+    active: HashMap<u64, u64>
+}
+
+/// Append-only redo/undo log shared by every table of a database.
+///
+/// The file starts with the lowest lsn the next record may take, followed by length prefixed
+/// records. Checkpoints truncate the records they made redundant.
+#[derive(Debug)]
+pub struct Wal {
+    path: PathBuf,
+    inner: Mutex<WalInner>
+}
+
+impl Wal {
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+        if file.metadata()?.len() < HEADSIZ {
+            file.set_len(0)?;
+            file.write_all(&1u64.to_le_bytes())?;
+            file.sync_data()?;
+        }
+        let (base, records) = Self::scan(&mut file)?;
+        let next_lsn = records.last().map_or(base, |r| base.max(r.lsn + 1));
+        let next_txn = records.iter().map(|r| r.txn + 1).max().unwrap_or(1);
+        Ok(Self { path: path.to_path_buf(), inner: Mutex::new(WalInner { file, next_lsn, next_txn, flushed_lsn: next_lsn - 1, pending: vec![], active: HashMap::new() }) })
+    }
+
+    fn scan(file: &mut File) -> Result<(u64, Vec<LogRecord>), Error> {
+        let mut bytes = Vec::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_end(&mut bytes)?;
+        let base = u64::from_le_bytes(bytes[..HEADSIZ as usize].try_into().unwrap());
+        let mut records = vec![];
+        let mut pos = HEADSIZ as usize;
+        // a torn record at the tail is what a crash mid-append leaves behind, so it ends the log
+        while pos + 4 <= bytes.len() {
+            let len = u32::from_le_bytes(bytes[pos..pos+4].try_into().unwrap()) as usize;
+            let Some(frame) = bytes.get(pos+4..pos+4+len) else { break; };
+            let Ok(record) = bincode::deserialize::<LogRecord>(frame) else { break; };
+            records.push(record);
+            pos += 4 + len;
+        }
+        Ok((base, records))
+    }
+
+    pub fn begin(&self) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        inner.next_txn += 1;
+        inner.next_txn - 1
+    }
+
+    pub fn append(&self, txn: u64, body: LogBody) -> u64 {
+        let mut inner = self.inner.lock().unwrap();
+        let lsn = inner.next_lsn;
+        inner.next_lsn += 1;
+        if matches!(body, LogBody::Commit | LogBody::Abort) { inner.active.remove(&txn); } else { inner.active.entry(txn).or_insert(lsn); }
+        let bytes = bincode::serialize(&LogRecord { lsn, txn, body }).unwrap();
+        inner.pending.extend_from_slice(&(bytes.len() as u32).to_le_bytes());
+        inner.pending.extend_from_slice(&bytes);
+        lsn
+    }
+
+    pub fn commit(&self, txn: u64) -> Result<(), Error> {
+        let lsn = self.append(txn, LogBody::Commit);
+        self.flush(lsn)
+    }
+
+    /// Ends a transaction whose changes were rolled back. The record need not be durable: without
+    /// it recovery undoes the reversals along with the changes they reverted.
+    pub fn abort(&self, txn: u64) {
+        if self.inner.lock().unwrap().active.contains_key(&txn) { self.append(txn, LogBody::Abort); }
+    }
+
+    /// Makes every record up to and including `lsn` durable.
+    pub fn flush(&self, lsn: u64) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        if lsn <= inner.flushed_lsn { return Ok(()); }
+        let pending = std::mem::take(&mut inner.pending);
+        inner.file.write_all(&pending)?;
+        inner.file.sync_data()?;
+        inner.flushed_lsn = inner.next_lsn - 1;
+        Ok(())
+    }
+
+    pub fn records(&self) -> Result<Vec<LogRecord>, Error> {
+        self.flush(u64::MAX)?;
+        let mut inner = self.inner.lock().unwrap();
+        Ok(Self::scan(&mut inner.file)?.1)
+    }
+
+    /// The lsn the next record gets.
+    pub fn next_lsn(&self) -> u64 {
+        self.inner.lock().unwrap().next_lsn
+    }
+
+    /// Drops the records older than `upto` once every page they touched is durable, except those
+    /// of transactions still in flight, which undo may need. The log is rewritten aside and renamed
+    /// over, so a crash leaves either version.
+    pub fn truncate(&self, upto: u64) -> Result<(), Error> {
+        self.flush(u64::MAX)?;
+        let mut inner = self.inner.lock().unwrap();
+        let (_, records) = Self::scan(&mut inner.file)?;
+        let mut bytes = inner.next_lsn.to_le_bytes().to_vec();
+        for r in records.iter().filter(|r| r.lsn >= upto || inner.active.contains_key(&r.txn)) {
+            let record = bincode::serialize(r).unwrap();
+            bytes.extend_from_slice(&(record.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&record);
+        }
+        let tmp = self.path.with_extension("tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&bytes)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+        inner.file = OpenOptions::new().read(true).append(true).open(&self.path)?;
+        Ok(())
+    }
+
+    /// Drops every record, keeping lsns monotonic across the truncation.
+    pub fn reset(&self) -> Result<(), Error> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.pending.clear();
+        inner.file.set_len(0)?;
+        let base = inner.next_lsn;
+        inner.file.write_all(&base.to_le_bytes())?;
+        inner.file.sync_data()?;
+        inner.flushed_lsn = base - 1;
+        Ok(())
+    }
+}
+
+fn load<'a>(db: &Database, pages: &'a mut HashMap<u128, Page>, page_id: u128) -> Result<Option<&'a mut Page>, Error> {
+    if let Entry::Vacant(e) = pages.entry(page_id) {
+        let Ok(blocks) = file_blocks(db, &(page_id>>64).to_string()) else { return Ok(None); };
+        if blocks <= (page_id & SET_64 as u128) as u64 { return Ok(None); }
+        e.insert(Page { page_id: Some(page_id), block: Some(read_block(db, page_id)?) });
+    }
+    Ok(pages.get_mut(&page_id))
+}
+
+fn redo_append(db: &Database, head_ino: u64, data_ino: u64, block_num: u64) -> Result<(), Error> {
+    let Ok(mut len) = file_blocks(db, &data_ino.to_string()) else { return Ok(()); };
+    while len <= block_num {
+        append_block(db, &data_ino.to_string())?;
+        len += 1;
+    }
+    if head_ino == 0 { return Ok(()); }
+    let Ok(bytes) = read_file(db, &head_ino.to_string()) else { return Ok(()); };
+    let Ok(mut table) = bincode::deserialize::<RowTable>(&bytes) else { return Ok(()); };
+    if table.num_blocks <= block_num {
+        table.num_blocks = block_num + 1;
+        table.free.resize(table.num_blocks as usize, DATSIZ as u16);
+        write_file(db, &head_ino.to_string(), &bincode::serialize(&table).unwrap())?;
+    }
+    Ok(())
+}
+
+fn redo_truncate(db: &Database, head_ino: u64, data_ino: u64) -> Result<(), Error> {
+    if !file_exists(db, &data_ino.to_string()) { return Ok(()); }
+    create_file(db, &data_ino.to_string())?;
+    let Ok(bytes) = read_file(db, &head_ino.to_string()) else { return Ok(()); };
+    let Ok(mut table) = bincode::deserialize::<RowTable>(&bytes) else { return Ok(()); };
+    table.reset();
+    write_file(db, &head_ino.to_string(), &bincode::serialize(&table).unwrap())?;
+    Ok(())
+}
+
+/// Replays the log against the data files and rolls back transactions that never finished.
+///
+/// Redo repeats every change newer than the lsn of the page it touches, undo then reverts the
+/// unfinished ones newest first. An aborted transaction logged its own reversals, which redo
+/// repeated, so it counts as finished. Pages are synced before the log is truncated.
+pub fn recover(db: &Database) -> Result<(), Error> {
+    let records = db.wal().records()?;
+    if records.is_empty() { return Ok(()); }
+    let finished: HashSet<u64> = records.iter().filter(|r| matches!(r.body, LogBody::Commit | LogBody::Abort)).map(|r| r.txn).collect();
+    let mut pages = HashMap::new();
+
+    for r in records.iter() {
+        match &r.body {
+            LogBody::AppendBlock { head_ino, data_ino, block_num } => redo_append(db, *head_ino, *data_ino, *block_num)?,
+            LogBody::Truncate { head_ino, data_ino } => {
+                pages.retain(|page_id, _| (page_id >> 64) as u64 != *data_ino);
+                redo_truncate(db, *head_ino, *data_ino)?;
+            },
+            LogBody::Insert { page_id, slot, data } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                if p.lsn() >= r.lsn { continue; }
+                p.write_slot(*slot, data)?;
+                p.set_lsn(r.lsn);
+            },
+            LogBody::Update { page_id, slot, after, .. } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                if p.lsn() >= r.lsn { continue; }
+                p.update(*slot, after)?;
+                p.set_lsn(r.lsn);
+            },
+            LogBody::Delete { page_id, slot, .. } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                if p.lsn() >= r.lsn { continue; }
+                p.delete(*slot)?;
+                p.set_lsn(r.lsn);
+            },
+            LogBody::Compact { page_id } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                if p.lsn() >= r.lsn { continue; }
+                p.compact()?;
+                p.set_lsn(r.lsn);
+            },
+            LogBody::Overflow { page_id, next, data } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                if p.lsn() >= r.lsn { continue; }
+                p.write_slot(0, data)?;
+                if let Some(next) = next { p.set_next(*next); }
+                p.set_lsn(r.lsn);
+            },
+            LogBody::Commit | LogBody::Abort => {}
+        }
+    }
+
+    for r in records.iter().rev().filter(|r| !finished.contains(&r.txn)) {
+        match &r.body {
+            LogBody::Insert { page_id, slot, .. } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                p.delete(*slot)?;
+            },
+            LogBody::Update { page_id, slot, before, .. } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                p.update(*slot, before)?;
+            },
+            LogBody::Delete { page_id, slot, loc } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                p.set_location(*slot, *loc)?;
+            },
+            // the piece of a string spilled by an undone row is deleted for vacuum to hand its block out again
+            LogBody::Overflow { page_id, .. } => {
+                let Some(p) = load(db, &mut pages, *page_id)? else { continue; };
+                p.delete(0)?;
+            },
+            LogBody::AppendBlock { .. } | LogBody::Compact { .. } | LogBody::Truncate { .. } | LogBody::Commit | LogBody::Abort => {}
+        }
+    }
+
+    let mut files = HashSet::new();
+    for (page_id, page) in pages.iter_mut() {
+        let block: &mut Block = page.block.as_mut().unwrap();
+        if block.check_flag(&Flags::Dirty) { block.toggle_flag(&Flags::Dirty); }
+        write_block(db, *page_id, block)?;
+        files.insert(page_id>>64);
+    }
+    for f_id in files {
+        sync_file(db, &f_id.to_string())?;
+    }
+    db.wal().reset()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use crate::{storage::{database::{test_db, Database}, folder::Folder, disk_manager::{read_block, write_block}}, buffer::{tuple::{RowTable, DatumTypes, Datum, TupleOps, PageBuffer, Table}, page::{Page, TupleCRUD}, Buff}};
+
+    use super::{LogBody, LogRecord};
+
+    #[test]
+    fn test_wal_append() {
+        let db = test_db("wal_append");
+        let txn = db.wal().begin();
+        let lsn = db.wal().append(txn, LogBody::Delete { page_id: 1, slot: 0, loc: 10 });
+        db.wal().commit(txn).unwrap();
+        db.wal().reset().unwrap();
+        let lsn1 = db.wal().append(txn, LogBody::Commit);
+        assert_eq!(db.wal().records().unwrap(), vec![LogRecord { lsn: lsn1, txn, body: LogBody::Commit }]);
+        assert_eq!(lsn1, lsn + 2);
+        let db = Database::open(db.root()).unwrap();
+        assert_eq!(db.wal().append(txn, LogBody::Commit), lsn1 + 1);
+    }
+
+    #[test]
+    fn test_wal_truncate() {
+        let db = test_db("wal_truncate");
+        let done = db.wal().begin();
+        db.wal().append(done, LogBody::Compact { page_id: 1 });
+        db.wal().commit(done).unwrap();
+        let open = db.wal().begin();
+        let first = db.wal().append(open, LogBody::Compact { page_id: 2 });
+        let upto = db.wal().next_lsn();
+        let other = db.wal().begin();
+        let late = db.wal().append(other, LogBody::Compact { page_id: 3 });
+        db.wal().truncate(upto).unwrap();
+        // the committed transaction is gone, the one in flight and everything newer stays
+        let lsns: Vec<_> = db.wal().records().unwrap().iter().map(|r| r.lsn).collect();
+        assert_eq!(lsns, vec![first, late]);
+
+        db.wal().commit(open).unwrap();
+        db.wal().commit(other).unwrap();
+        db.wal().truncate(db.wal().next_lsn()).unwrap();
+        assert!(db.wal().records().unwrap().is_empty());
+        let next = db.wal().next_lsn();
+        let db = Database::open(db.root()).unwrap();
+        assert_eq!(db.wal().next_lsn(), next);
+    }
+
+    #[test]
+    fn test_recover_redo() {
+        let db = test_db("wal_recover_redo");
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        RowTable::create(Arc::clone(&f), "redo", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        f.update_table("redo", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(10)])).unwrap();
+        // the dirty page never leaves the buffer, as if the process crashed here
+        let db = Database::open(db.root()).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        let t = RowTable::new(Arc::clone(&f), "redo").unwrap();
+        assert_eq!(t.num_blocks, 1);
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        assert_eq!(t.iter(buf).collect::<Vec<_>>(), vec![vec![Datum::Int(10)]]);
+    }
+
+    #[test]
+    fn test_recover_truncate() {
+        let db = test_db("wal_recover_truncate");
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        RowTable::create(Arc::clone(&f), "trunc", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        f.update_table("trunc", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(10)])).unwrap();
+        f.update_table("trunc", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(20)])).unwrap();
+        f.truncate_table::<RowTable>("trunc", &buf).unwrap();
+        f.update_table("trunc", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(30)])).unwrap();
+        // crash with the new row only in the log, the older rows must not come back
+        let db = Database::open(db.root()).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        let t = RowTable::new(Arc::clone(&f), "trunc").unwrap();
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        assert_eq!(t.iter(buf).collect::<Vec<_>>(), vec![vec![Datum::Int(30)]]);
+    }
+
+    #[test]
+    fn test_recover_redo_slot() {
+        let db = test_db("wal_recover_redo_slot");
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        let t = RowTable::create(Arc::clone(&f), "redo_slot", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        f.update_table("redo_slot", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(10)])).unwrap();
+        f.update_table("redo_slot", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(20)])).unwrap();
+        f.update_table("redo_slot", |t: &mut RowTable| t.delete(Arc::clone(&buf), 0, 0)).unwrap();
+        buf.flush().unwrap();
+        // the row went to a new slot while the first one was still dead on the logging side
+        let page_id = (t.inode().data_ino as u128) << 64;
+        let data = Page::encode(&vec![Datum::Int(30)], &t.schema).unwrap();
+        let txn = db.wal().begin();
+        db.wal().append(txn, LogBody::Insert { page_id, slot: 2, data: data.clone() });
+        db.wal().commit(txn).unwrap();
+        Folder::new(Arc::clone(&db)).unwrap();
+        let p = Page { page_id: Some(page_id), block: Some(read_block(&db, page_id).unwrap()) };
+        assert_eq!(p.read(0).unwrap(), None);
+        assert_eq!(p.read(2).unwrap(), Some(data));
+    }
+
+    #[test]
+    fn test_recover_undo() {
+        let db = test_db("wal_recover_undo");
+        Folder::create(Arc::clone(&db)).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        let t = RowTable::create(Arc::clone(&f), "undo", vec![("a".into(), DatumTypes::Int)]).unwrap();
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        f.update_table("undo", |t: &mut RowTable| t.add(Arc::clone(&buf), vec![Datum::Int(10)])).unwrap();
+        buf.flush().unwrap();
+        let page_id = (t.inode().data_ino as u128) << 64;
+        let mut p = Page { page_id: Some(page_id), block: Some(read_block(&db, page_id).unwrap()) };
+        p.write(&20i32.to_le_bytes()).unwrap();
+        let txn = db.wal().begin();
+        let lsn = db.wal().append(txn, LogBody::Insert { page_id, slot: 1, data: 20i32.to_le_bytes().to_vec() });
+        db.wal().flush(lsn).unwrap();
+        p.set_lsn(lsn);
+        write_block(&db, page_id, p.block.as_ref().unwrap()).unwrap();
+        let f = Arc::new(Folder::new(Arc::clone(&db)).unwrap());
+        let t = RowTable::new(f, "undo").unwrap();
+        let buf = Arc::new(PageBuffer::new(Arc::clone(&db), 10));
+        assert_eq!(t.iter(buf).collect::<Vec<_>>(), vec![vec![Datum::Int(10)]]);
+    }
+}